@@ -1,5 +1,6 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use folds::cpu::StampFolder;
+use folds::backend::{self, FoldRequest};
+use folds::cpu::{GapMode, StampFolder};
 
 fn benchmark_rectangular(c: &mut Criterion) {
     let mut group = c.benchmark_group("Rectangular Dimensions");
@@ -46,9 +47,42 @@ fn benchmark_comparison(c: &mut Criterion) {
     group.finish();
 }
 
+fn benchmark_backends(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Backends");
+
+    // Same request through every backend compiled in (and usable on this machine)
+    for name in backend::available() {
+        let Ok(backend) = backend::backend(name) else { continue };
+        let mut request = FoldRequest::whole(&[3, 4]);
+        request.options.threads = Some(4);
+        group.bench_function(format!("{} 3x4", name), |b| {
+            b.iter(|| backend.count(black_box(&request)).unwrap());
+        });
+    }
+
+    group.finish();
+}
+
+fn benchmark_gap_modes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Gap Computation");
+
+    // Single-threaded, so only the gap computation differs
+    for dimensions in [[2, 6], [3, 4], [4, 4]] {
+        for (name, mode) in [("list", GapMode::List), ("bitmask", GapMode::Bitmask)] {
+            group.bench_function(format!("{} {}x{}", name, dimensions[0], dimensions[1]), |b| {
+                b.iter(|| StampFolder::calculate_sequence_with(black_box(&dimensions), mode));
+            });
+        }
+    }
+
+    group.finish();
+}
+
 criterion_group!(
     extended_benches,
     benchmark_rectangular,
-    benchmark_comparison
+    benchmark_comparison,
+    benchmark_backends,
+    benchmark_gap_modes
 );
 criterion_main!(extended_benches);
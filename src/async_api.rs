@@ -0,0 +1,214 @@
+//! Async facade for embedding the solver in tokio services.
+//!
+//! The search runs on tokio's blocking pool (and rayon inside it), so awaiting a count never
+//! blocks the runtime. Progress is published as a stream and a count can be cancelled.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rayon::prelude::*;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::Stream;
+
+use crate::certificate::check_partition;
+use crate::cpu::PartSearch;
+
+// Placements between cancellation checks and progress updates
+const SLICE_NODES: u64 = 1 << 18;
+
+#[derive(Clone, Debug, Default)]
+pub struct CountOptions {
+    /// Number of `res/mod` parts; `None` picks one per CPU (capped by the map size).
+    pub parts: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FoldingResult {
+    pub dims: Vec<i32>,
+    pub count: i64,
+    pub nodes: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Progress {
+    pub parts_done: usize,
+    pub total_parts: usize,
+    /// Estimated fraction of the whole count that is finished.
+    pub fraction: f64,
+    pub nodes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    Cancelled,
+    InvalidPartition { dims: Vec<i32>, total_parts: usize },
+    /// The blocking task panicked or was aborted.
+    Task(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Cancelled => write!(f, "count was cancelled"),
+            Error::InvalidPartition { dims, total_parts } => {
+                write!(f, "cannot split {:?} into {} parts", dims, total_parts)
+            }
+            Error::Task(message) => write!(f, "solver task failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Cloneable handle that stops a running count at its next slice boundary.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A count running in the background.
+pub struct CountHandle {
+    task: JoinHandle<Result<FoldingResult>>,
+    progress: watch::Receiver<Progress>,
+    cancel: CancelHandle,
+}
+
+impl CountHandle {
+    /// Stream of progress snapshots; it ends when the count finishes.
+    pub fn progress(&self) -> impl Stream<Item = Progress> {
+        WatchStream::new(self.progress.clone())
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    pub async fn result(self) -> Result<FoldingResult> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => Err(Error::Task(e.to_string())),
+        }
+    }
+}
+
+/// Counts all foldings of `dims` without blocking the runtime.
+pub async fn count(dims: &[i32], opts: CountOptions) -> Result<FoldingResult> {
+    spawn_count(dims, opts)?.result().await
+}
+
+/// Starts a count on the blocking pool; must be called from within a tokio runtime.
+pub fn spawn_count(dims: &[i32], opts: CountOptions) -> Result<CountHandle> {
+    let n = dims.iter().product::<i32>().max(1) as usize;
+    let total_parts = opts.parts.unwrap_or_else(|| num_cpus::get().min(n));
+    check_partition(dims, total_parts)
+        .map_err(|_| Error::InvalidPartition { dims: dims.to_vec(), total_parts })?;
+
+    let (sender, progress) = watch::channel(Progress { total_parts, ..Progress::default() });
+    let cancel = CancelHandle::default();
+    let dims = dims.to_vec();
+    let worker_cancel = cancel.clone();
+    let task = tokio::task::spawn_blocking(move || run(dims, total_parts, &worker_cancel, &sender));
+
+    Ok(CountHandle { task, progress, cancel })
+}
+
+fn run(
+    dims: Vec<i32>,
+    total_parts: usize,
+    cancel: &CancelHandle,
+    sender: &watch::Sender<Progress>,
+) -> Result<FoldingResult> {
+    // Per-part fraction and node count, folded into one snapshot on every update
+    let parts = Mutex::new(vec![(0.0, 0u64); total_parts]);
+    let publish = |part: usize, fraction: f64, nodes: u64| {
+        let mut parts = parts.lock().unwrap();
+        parts[part] = (fraction, nodes);
+        sender.send_replace(Progress {
+            parts_done: parts.iter().filter(|(fraction, _)| *fraction >= 1.0).count(),
+            total_parts,
+            fraction: parts.iter().map(|(fraction, _)| fraction).sum::<f64>() / total_parts as f64,
+            nodes: parts.iter().map(|(_, nodes)| nodes).sum(),
+        });
+    };
+
+    // Rayon workers do not inherit the caller's span, so parts name it as their parent
+    let span = tracing::debug_span!("count", ?dims, total_parts);
+    let stats = (0..total_parts)
+        .into_par_iter()
+        .map(|part| {
+            let _span = tracing::debug_span!(parent: &span, "part", part).entered();
+            let mut search = PartSearch::new(&dims, part, total_parts);
+            loop {
+                if cancel.is_cancelled() {
+                    tracing::debug!("cancelled");
+                    return Err(Error::Cancelled);
+                }
+                let finished = search.step(SLICE_NODES);
+                publish(part, if finished { 1.0 } else { search.fraction_done() }, search.stats().nodes);
+                if finished {
+                    return Ok(search.stats());
+                }
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(FoldingResult {
+        dims,
+        count: stats.iter().map(|s| s.count).sum(),
+        nodes: stats.iter().map(|s| s.nodes).sum(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_count() {
+        let result = count(&[3, 3], CountOptions::default()).await.unwrap();
+        assert_eq!(result.count, 1368);
+
+        let result = count(&[2, 4], CountOptions { parts: Some(3) }).await.unwrap();
+        assert_eq!(result.count, 320);
+
+        assert!(matches!(
+            count(&[2, 2], CountOptions { parts: Some(9) }).await,
+            Err(Error::InvalidPartition { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_progress_stream_ends_complete() {
+        let handle = spawn_count(&[3, 4], CountOptions { parts: Some(4) }).unwrap();
+        let progress = handle.progress();
+        let result = handle.result().await.unwrap();
+
+        let last = progress.fold(None, |_, p| Some(p)).await.unwrap();
+        assert_eq!(result.count, 15552);
+        assert_eq!(last.parts_done, 4);
+        assert_eq!(last.fraction, 1.0);
+        assert_eq!(last.nodes, result.nodes);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        // Each part of 6x6 runs far past one slice, so the cancel is seen before any finishes
+        let handle = spawn_count(&[6, 6], CountOptions { parts: Some(2) }).unwrap();
+        handle.cancel_handle().cancel();
+        assert_eq!(handle.result().await, Err(Error::Cancelled));
+    }
+}
@@ -0,0 +1,213 @@
+//! One request/result shape for every solver, so callers can pick a backend at runtime.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Partition {
+    Whole,
+    /// The `res/mod` split used by `folds r/m dims`.
+    Part { part: usize, total_parts: usize },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FoldOptions {
+    /// Worker threads for backends that use them; `None` lets the backend decide.
+    pub threads: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FoldRequest {
+    pub dims: Vec<i32>,
+    pub partition: Partition,
+    pub options: FoldOptions,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FoldResult {
+    pub count: u128,
+    /// Search nodes visited, when the backend tracks them.
+    pub nodes: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendError {
+    UnknownBackend(String),
+    /// The backend exists but cannot run here (not compiled in, or no device).
+    Unavailable(String),
+    InvalidRequest(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::UnknownBackend(name) => write!(f, "unknown backend `{}` (expected cpu or gpu)", name),
+            BackendError::Unavailable(message) => write!(f, "backend unavailable: {}", message),
+            BackendError::InvalidRequest(message) => write!(f, "invalid request: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+pub trait FoldBackend {
+    fn name(&self) -> &'static str;
+
+    fn count(&self, request: &FoldRequest) -> Result<FoldResult, BackendError>;
+}
+
+impl FoldRequest {
+    pub fn whole(dims: &[i32]) -> Self {
+        FoldRequest { dims: dims.to_vec(), partition: Partition::Whole, options: FoldOptions::default() }
+    }
+
+    pub fn part(dims: &[i32], part: usize, total_parts: usize) -> Self {
+        FoldRequest {
+            dims: dims.to_vec(),
+            partition: Partition::Part { part, total_parts },
+            options: FoldOptions::default(),
+        }
+    }
+
+    /// Checks the limits shared by all backends: n < 64 and a partition with 1 <= parts <= n.
+    pub fn validate(&self) -> Result<(), BackendError> {
+        if self.dims.is_empty() || self.dims.iter().any(|&d| d < 0) {
+            return Err(BackendError::InvalidRequest(format!("invalid dimensions {:?}", self.dims)));
+        }
+        let n: i64 = self.dims.iter().map(|&d| d as i64).product();
+        if n >= 64 {
+            return Err(BackendError::InvalidRequest(format!("{:?} has more than 63 leaves", self.dims)));
+        }
+        if let Partition::Part { part, total_parts } = self.partition {
+            if total_parts == 0 || part >= total_parts || (n > 0 && total_parts as i64 > n) {
+                return Err(BackendError::InvalidRequest(format!(
+                    "cannot run part {} of {} for {:?}",
+                    part, total_parts, self.dims
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names accepted by `backend`, in the order they are compiled in.
+pub fn available() -> Vec<&'static str> {
+    let mut names = Vec::new();
+    if cfg!(feature = "cpu") {
+        names.push("cpu");
+    }
+    if cfg!(feature = "gpu") {
+        names.push("gpu");
+    }
+    names
+}
+
+pub fn backend(name: &str) -> Result<Box<dyn FoldBackend>, BackendError> {
+    match name {
+        #[cfg(feature = "cpu")]
+        "cpu" => Ok(Box::new(CpuBackend)),
+        #[cfg(feature = "gpu")]
+        "gpu" => Ok(Box::new(GpuBackend::new(false)?)),
+        #[allow(unreachable_patterns)]
+        "cpu" | "gpu" => Err(BackendError::Unavailable(format!("built without the `{}` feature", name))),
+        _ => Err(BackendError::UnknownBackend(name.to_string())),
+    }
+}
+
+#[cfg(feature = "cpu")]
+pub struct CpuBackend;
+
+#[cfg(feature = "cpu")]
+impl FoldBackend for CpuBackend {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn count(&self, request: &FoldRequest) -> Result<FoldResult, BackendError> {
+        use crate::cpu::StampFolder;
+
+        request.validate()?;
+        let _span = tracing::debug_span!("count", backend = "cpu", dims = ?request.dims).entered();
+        let dims = &request.dims;
+        let stats = match request.partition {
+            Partition::Part { part, total_parts } => StampFolder::calculate_part_stats(dims, part, total_parts),
+            // Subtrees are handed to idle workers as they appear, on a pool of the requested size
+            Partition::Whole => match request.options.threads {
+                Some(threads) if threads != rayon::current_num_threads() => rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|e| BackendError::Unavailable(e.to_string()))?
+                    .install(|| StampFolder::calculate_adaptive_stats(dims)),
+                _ => StampFolder::calculate_adaptive_stats(dims),
+            },
+        };
+        Ok(FoldResult { count: stats.count as u128, nodes: Some(stats.nodes) })
+    }
+}
+
+#[cfg(feature = "gpu")]
+pub struct GpuBackend {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+}
+
+#[cfg(feature = "gpu")]
+impl GpuBackend {
+    /// Opens a device; `force_fallback_adapter` selects a software adapter where one exists.
+    pub fn new(force_fallback_adapter: bool) -> Result<Self, BackendError> {
+        match pollster::block_on(crate::gpu::request_device(force_fallback_adapter)) {
+            Some((device, queue)) => Ok(GpuBackend { device, queue }),
+            None => Err(BackendError::Unavailable("no suitable GPU adapter".to_string())),
+        }
+    }
+}
+
+#[cfg(feature = "gpu")]
+impl FoldBackend for GpuBackend {
+    fn name(&self) -> &'static str {
+        "gpu"
+    }
+
+    fn count(&self, request: &FoldRequest) -> Result<FoldResult, BackendError> {
+        request.validate()?;
+        let _span = tracing::debug_span!("count", backend = "gpu", dims = ?request.dims).entered();
+        let (part, total_parts) = match request.partition {
+            Partition::Whole => (0, 1),
+            Partition::Part { part, total_parts } => (part, total_parts),
+        };
+        let count = pollster::block_on(crate::gpu::StampFolder::calculate_sequence_part(
+            &self.device,
+            &self.queue,
+            &request.dims,
+            part,
+            total_parts,
+        ));
+        Ok(FoldResult { count, nodes: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_validation() {
+        assert!(FoldRequest::whole(&[3, 3]).validate().is_ok());
+        assert!(FoldRequest::part(&[3, 3], 8, 9).validate().is_ok());
+        assert!(FoldRequest::part(&[3, 3], 9, 9).validate().is_err());
+        assert!(FoldRequest::part(&[2, 2], 0, 5).validate().is_err());
+        assert!(FoldRequest::whole(&[8, 8]).validate().is_err());
+        assert!(matches!(backend("tpu"), Err(BackendError::UnknownBackend(_))));
+    }
+
+    #[cfg(feature = "cpu")]
+    #[test]
+    fn test_cpu_backend() {
+        let cpu = backend("cpu").unwrap();
+        assert_eq!(cpu.count(&FoldRequest::whole(&[3, 3])).unwrap().count, 1368);
+
+        let whole = cpu.count(&FoldRequest::whole(&[2, 4])).unwrap();
+        let parts: u128 = (0..3).map(|part| cpu.count(&FoldRequest::part(&[2, 4], part, 3)).unwrap().count).sum();
+        assert_eq!(whole.count, 320);
+        assert_eq!(parts, 320);
+    }
+}
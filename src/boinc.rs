@@ -0,0 +1,248 @@
+//! Running a part as a BOINC science application.
+//!
+//! Only the file-based conventions are used, so a slot directory laid out by hand behaves
+//! like one prepared by the BOINC client:
+//!
+//! * logical files (`in`, `out`) may be BOINC soft links (`<soft_link>path</soft_link>`),
+//! * the work unit is a `folds-workunit v1` file naming the dimensions and the part,
+//! * progress is written to `fraction_done` and the search state to `checkpoint`,
+//! * the result is a `folds-result v1` record (see `merge`), followed by `boinc_finish_called`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::certificate::{Leaf, UnitSpec, CODE_VERSION};
+use crate::cpu::PartSearch;
+use crate::merge;
+
+pub const WORKUNIT_HEADER: &str = "folds-workunit v1";
+pub const INPUT_FILE: &str = "in";
+pub const OUTPUT_FILE: &str = "out";
+pub const CHECKPOINT_FILE: &str = "checkpoint";
+pub const FRACTION_DONE_FILE: &str = "fraction_done";
+pub const FINISH_FILE: &str = "boinc_finish_called";
+
+// Placements between looks at the clock
+const SLICE_NODES: u64 = 1 << 20;
+// The client polls progress about once a second, so there is no point writing it more often
+const FRACTION_DONE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub checkpoint_period: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { checkpoint_period: Duration::from_secs(60) }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Maps a logical file name to the physical file, following a BOINC soft link if present.
+pub fn resolve_filename(slot: &Path, logical: &str) -> io::Result<PathBuf> {
+    let path = slot.join(logical);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(path),
+        Err(e) => return Err(e),
+    };
+
+    let target = content
+        .trim()
+        .strip_prefix("<soft_link>")
+        .and_then(|rest| rest.strip_suffix("</soft_link>"));
+    Ok(match target {
+        Some(target) => slot.join(target.trim()),
+        None => path,
+    })
+}
+
+pub fn workunit_to_text(spec: &UnitSpec) -> String {
+    let dims: Vec<String> = spec.dims.iter().map(|d| d.to_string()).collect();
+    format!("{}\ndims {}\npart {}\nparts {}\n", WORKUNIT_HEADER, dims.join(" "), spec.part, spec.total_parts)
+}
+
+pub fn parse_workunit(text: &str) -> Result<UnitSpec, String> {
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    if lines.next() != Some(WORKUNIT_HEADER) {
+        return Err("not a folds work unit".to_string());
+    }
+
+    let (mut dims, mut part, mut total_parts) = (None, None, None);
+    for line in lines {
+        let (name, value) = line.split_once(' ').ok_or_else(|| format!("malformed line `{}`", line))?;
+        let value = value.trim();
+        match name {
+            "dims" => {
+                dims = Some(
+                    value
+                        .split_whitespace()
+                        .map(|d| d.parse())
+                        .collect::<Result<Vec<i32>, _>>()
+                        .map_err(|_| "invalid `dims`".to_string())?,
+                )
+            }
+            "part" => part = Some(value.parse().map_err(|_| "invalid `part`".to_string())?),
+            "parts" => total_parts = Some(value.parse().map_err(|_| "invalid `parts`".to_string())?),
+            _ => return Err(format!("unknown field `{}`", name)),
+        }
+    }
+
+    let spec = UnitSpec {
+        dims: dims.ok_or("missing `dims`")?,
+        part: part.ok_or("missing `part`")?,
+        total_parts: total_parts.ok_or("missing `parts`")?,
+    };
+    crate::certificate::check_partition(&spec.dims, spec.total_parts).map_err(|e| e.to_string())?;
+    if spec.part >= spec.total_parts {
+        return Err(format!("part {} is outside the partition", spec.part));
+    }
+    Ok(spec)
+}
+
+// Write-then-rename so an interrupted write never leaves a truncated file behind
+fn write_atomic(path: &Path, content: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
+
+fn load_checkpoint(slot: &Path, spec: &UnitSpec) -> io::Result<Option<PartSearch>> {
+    let text = match fs::read_to_string(slot.join(CHECKPOINT_FILE)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    // A corrupt checkpoint, or one for another work unit, only costs the work since the start
+    match PartSearch::restore(&text) {
+        Ok(search) if search.unit() == (spec.dims.as_slice(), spec.part, spec.total_parts) => Ok(Some(search)),
+        Ok(_) => Ok(None),
+        Err(e) => {
+            tracing::warn!(error = %e, "discarding corrupt checkpoint");
+            Ok(None)
+        }
+    }
+}
+
+/// Runs (or resumes) the work unit in `slot` and writes its result.
+pub fn run(slot: &Path, options: &Options) -> io::Result<Leaf> {
+    let input = resolve_filename(slot, INPUT_FILE)?;
+    let spec = parse_workunit(&fs::read_to_string(&input)?).map_err(invalid_data)?;
+    let _span = tracing::info_span!("workunit", dims = ?spec.dims, part = spec.part, parts = spec.total_parts).entered();
+
+    let mut search = match load_checkpoint(slot, &spec)? {
+        Some(search) => {
+            tracing::info!(nodes = search.stats().nodes, "resuming from checkpoint");
+            search
+        }
+        None => {
+            tracing::info!("starting");
+            PartSearch::new(&spec.dims, spec.part, spec.total_parts)
+        }
+    };
+
+    let (mut last_checkpoint, mut last_fraction) = (Instant::now(), Instant::now());
+    while !search.step(SLICE_NODES) {
+        if last_fraction.elapsed() >= FRACTION_DONE_PERIOD {
+            write_atomic(&slot.join(FRACTION_DONE_FILE), &format!("{:.6}\n", search.fraction_done()))?;
+            last_fraction = Instant::now();
+        }
+        if last_checkpoint.elapsed() >= options.checkpoint_period {
+            write_atomic(&slot.join(CHECKPOINT_FILE), &search.checkpoint())?;
+            tracing::debug!(fraction = search.fraction_done(), nodes = search.stats().nodes, "checkpoint written");
+            last_checkpoint = Instant::now();
+        }
+    }
+
+    let stats = search.stats();
+    tracing::info!(count = stats.count, nodes = stats.nodes, "finished");
+    let leaf = Leaf { spec, count: stats.count, nodes: stats.nodes, version: CODE_VERSION.to_string() };
+    write_atomic(&resolve_filename(slot, OUTPUT_FILE)?, &merge::record_to_text(&leaf))?;
+    write_atomic(&slot.join(FRACTION_DONE_FILE), "1.000000\n")?;
+    fs::write(slot.join(FINISH_FILE), "0\n")?;
+    Ok(leaf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("folds-boinc-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_run_with_soft_links() {
+        let dir = slot("links");
+        let spec = UnitSpec { dims: vec![3, 3], part: 3, total_parts: 5 };
+        fs::create_dir_all(dir.join("project")).unwrap();
+        fs::write(dir.join("project/wu_3"), workunit_to_text(&spec)).unwrap();
+        fs::write(dir.join(INPUT_FILE), "<soft_link>project/wu_3</soft_link>\n").unwrap();
+        fs::write(dir.join(OUTPUT_FILE), "<soft_link>project/result_3</soft_link>\n").unwrap();
+
+        let leaf = run(&dir, &Options::default()).unwrap();
+        let written = merge::parse_record(&fs::read_to_string(dir.join("project/result_3")).unwrap()).unwrap();
+        let fraction = fs::read_to_string(dir.join(FRACTION_DONE_FILE)).unwrap();
+        let finished = dir.join(FINISH_FILE).exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(written, leaf);
+        assert_eq!(leaf, Leaf::compute(&[3, 3], 3, 5));
+        assert_eq!(fraction.trim(), "1.000000");
+        assert!(finished);
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let dir = slot("resume");
+        let spec = UnitSpec { dims: vec![2, 4], part: 1, total_parts: 3 };
+        fs::write(dir.join(INPUT_FILE), workunit_to_text(&spec)).unwrap();
+
+        // Simulate a run that was killed after its last checkpoint
+        let mut search = PartSearch::new(&spec.dims, spec.part, spec.total_parts);
+        search.step(10);
+        fs::write(dir.join(CHECKPOINT_FILE), search.checkpoint()).unwrap();
+
+        let leaf = run(&dir, &Options::default()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(leaf, Leaf::compute(&[2, 4], 1, 3));
+    }
+
+    #[test]
+    fn test_corrupt_checkpoint_starts_fresh() {
+        let dir = slot("corrupt");
+        let spec = UnitSpec { dims: vec![2, 4], part: 1, total_parts: 3 };
+        fs::write(dir.join(INPUT_FILE), workunit_to_text(&spec)).unwrap();
+
+        let mut search = PartSearch::new(&spec.dims, spec.part, spec.total_parts);
+        search.step(10);
+        let checkpoint = search.checkpoint();
+        fs::write(dir.join(CHECKPOINT_FILE), &checkpoint[..checkpoint.len() / 2]).unwrap();
+
+        let leaf = run(&dir, &Options::default()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(leaf, Leaf::compute(&[2, 4], 1, 3));
+    }
+
+    #[test]
+    fn test_parse_workunit_rejects_bad_input() {
+        assert!(parse_workunit("folds-workunit v1\ndims 2 2\npart 4\nparts 4\n").is_err());
+        assert!(parse_workunit("folds-workunit v1\ndims 2 2\nparts 4\n").is_err());
+        assert!(parse_workunit("folds-workunit v1\ndims 8 8\npart 0\nparts 4\n").is_err());
+        assert!(parse_workunit("dims 2 2\npart 0\nparts 4\n").is_err());
+        assert_eq!(
+            parse_workunit("folds-workunit v1\ndims 2 2\npart 0\nparts 4\n"),
+            Ok(UnitSpec { dims: vec![2, 2], part: 0, total_parts: 4 })
+        );
+    }
+}
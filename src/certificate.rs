@@ -0,0 +1,470 @@
+use std::fmt;
+
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::cpu::{StampFolder, MAX_N};
+use crate::rng::SplitMix64;
+
+pub const HEADER: &str = "folds-certificate v1";
+pub const CODE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub type Hash = [u8; 32];
+
+// Domain separation so a leaf hash can never be confused with an inner node
+const LEAF_TAG: u8 = 0x00;
+const NODE_TAG: u8 = 0x01;
+
+/// One `res/mod` unit of a partitioned run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnitSpec {
+    pub dims: Vec<i32>,
+    pub part: usize,
+    pub total_parts: usize,
+}
+
+/// A certified unit result: what was run, what it produced and which code produced it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Leaf {
+    pub spec: UnitSpec,
+    pub count: i64,
+    pub nodes: u64,
+    pub version: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Certificate {
+    pub dims: Vec<i32>,
+    pub total_parts: usize,
+    pub version: String,
+    pub total: i64,
+    pub root: Hash,
+    pub leaves: Vec<Leaf>,
+}
+
+/// Outcome of a successful `Certificate::verify`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verification {
+    pub total: i64,
+    pub sampled_parts: Vec<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificateError {
+    Parse { line: usize, message: String },
+    InvalidDims(Vec<i32>),
+    InvalidPartition { dims: Vec<i32>, total_parts: usize },
+    MissingPart(usize),
+    DuplicatePart(usize),
+    PartOutOfRange(usize),
+    VersionMismatch { certificate: String, local: String },
+    RootMismatch,
+    TotalMismatch { claimed: i64, sum: i64 },
+    LeafMismatch { part: usize, claimed: (i64, u64), recomputed: (i64, u64) },
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertificateError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            CertificateError::InvalidDims(dims) => {
+                write!(f, "invalid dimensions {:?} (the search takes at most {} leaves)", dims, MAX_N - 1)
+            }
+            CertificateError::InvalidPartition { dims, total_parts } => {
+                write!(f, "cannot split {:?} into {} parts", dims, total_parts)
+            }
+            CertificateError::MissingPart(part) => write!(f, "part {} is missing", part),
+            CertificateError::DuplicatePart(part) => write!(f, "part {} appears more than once", part),
+            CertificateError::PartOutOfRange(part) => write!(f, "part {} is outside the partition", part),
+            CertificateError::VersionMismatch { certificate, local } => write!(
+                f,
+                "certificate was produced by version {}, this is version {}",
+                certificate, local
+            ),
+            CertificateError::RootMismatch => write!(f, "root hash does not match the leaves"),
+            CertificateError::TotalMismatch { claimed, sum } => {
+                write!(f, "claimed total {} but leaves sum to {}", claimed, sum)
+            }
+            CertificateError::LeafMismatch { part, claimed, recomputed } => write!(
+                f,
+                "part {}: certificate says count {} / nodes {}, recomputed count {} / nodes {}",
+                part, claimed.0, claimed.1, recomputed.0, recomputed.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CertificateError {}
+
+impl Leaf {
+    /// Runs one unit with the local code and records the result.
+    pub fn compute(dims: &[i32], part: usize, total_parts: usize) -> Self {
+        let stats = StampFolder::calculate_part_stats(dims, part, total_parts);
+        Leaf {
+            spec: UnitSpec { dims: dims.to_vec(), part, total_parts },
+            count: stats.count,
+            nodes: stats.nodes,
+            version: CODE_VERSION.to_string(),
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        let dims: Vec<String> = self.spec.dims.iter().map(|d| d.to_string()).collect();
+        let encoded = format!(
+            "dims={};part={};parts={};count={};nodes={};version={}",
+            dims.join("x"),
+            self.spec.part,
+            self.spec.total_parts,
+            self.count,
+            self.nodes,
+            self.version
+        );
+        let mut hasher = Sha256::new();
+        hasher.update([LEAF_TAG]);
+        hasher.update(encoded.as_bytes());
+        hasher.finalize().into()
+    }
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_TAG]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of the hash tree over `leaves`; an odd node at the end of a level is promoted unchanged.
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Sha256::digest([]).into();
+    }
+
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| if pair.len() == 2 { hash_pair(&pair[0], &pair[1]) } else { pair[0] })
+            .collect();
+    }
+    level[0]
+}
+
+/// Sibling hashes from leaf `index` up to the root; the flag is true when the sibling is on the left.
+pub fn inclusion_proof(leaves: &[Hash], mut index: usize) -> Vec<(Hash, bool)> {
+    let mut proof = Vec::new();
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            proof.push((level[sibling], sibling < index));
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| if pair.len() == 2 { hash_pair(&pair[0], &pair[1]) } else { pair[0] })
+            .collect();
+        index /= 2;
+    }
+    proof
+}
+
+pub fn verify_inclusion(leaf: &Hash, proof: &[(Hash, bool)], root: &Hash) -> bool {
+    let computed = proof.iter().fold(*leaf, |acc, (sibling, sibling_is_left)| {
+        if *sibling_is_left { hash_pair(sibling, &acc) } else { hash_pair(&acc, sibling) }
+    });
+    &computed == root
+}
+
+pub fn to_hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Hash> {
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Checks that the search can take on a map of `dims` (no negative dimension and fewer than 64
+/// leaves) and returns its number of leaves.
+pub fn check_dims(dims: &[i32]) -> Result<usize, CertificateError> {
+    let n = if dims.contains(&0) { Some(0) } else { dims.iter().try_fold(1i64, |n, &d| n.checked_mul(d as i64)) };
+    match n {
+        Some(n) if !dims.is_empty() && dims.iter().all(|&d| d >= 0) && n < MAX_N as i64 => Ok(n as usize),
+        _ => Err(CertificateError::InvalidDims(dims.to_vec())),
+    }
+}
+
+/// The res/mod split is only exact while every residue can be placed, i.e. for 1 <= parts <= n.
+pub fn check_partition(dims: &[i32], total_parts: usize) -> Result<(), CertificateError> {
+    let n = check_dims(dims)?;
+    if total_parts >= 1 && (n == 0 || total_parts <= n) {
+        Ok(())
+    } else {
+        Err(CertificateError::InvalidPartition { dims: dims.to_vec(), total_parts })
+    }
+}
+
+impl Certificate {
+    /// Runs every part of the partition in parallel and certifies the total.
+    pub fn compute(dims: &[i32], total_parts: usize) -> Result<Self, CertificateError> {
+        check_partition(dims, total_parts)?;
+        let leaves: Vec<Leaf> = (0..total_parts)
+            .into_par_iter()
+            .map(|part| Leaf::compute(dims, part, total_parts))
+            .collect();
+        Self::from_leaves(dims, total_parts, leaves)
+    }
+
+    /// Builds a certificate from unit results, which must cover the partition exactly once.
+    pub fn from_leaves(dims: &[i32], total_parts: usize, mut leaves: Vec<Leaf>) -> Result<Self, CertificateError> {
+        check_partition(dims, total_parts)?;
+        leaves.sort_by_key(|leaf| leaf.spec.part);
+        check_coverage(dims, total_parts, &leaves)?;
+
+        let version = leaves.first().map(|leaf| leaf.version.clone()).unwrap_or_default();
+        if let Some(leaf) = leaves.iter().find(|leaf| leaf.version != version) {
+            return Err(CertificateError::VersionMismatch { certificate: version, local: leaf.version.clone() });
+        }
+
+        let hashes: Vec<Hash> = leaves.iter().map(Leaf::hash).collect();
+        tracing::debug!(?dims, total_parts, "certified");
+        Ok(Certificate {
+            dims: dims.to_vec(),
+            total_parts,
+            version,
+            total: leaves.iter().map(|leaf| leaf.count).sum(),
+            root: merkle_root(&hashes),
+            leaves,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let dims: Vec<String> = self.dims.iter().map(|d| d.to_string()).collect();
+        let mut text = format!(
+            "{}\nversion {}\ndims {}\nparts {}\ntotal {}\nroot {}\n",
+            HEADER,
+            self.version,
+            dims.join(" "),
+            self.total_parts,
+            self.total,
+            to_hex(&self.root)
+        );
+        for leaf in &self.leaves {
+            text.push_str(&format!("leaf {} {} {}\n", leaf.spec.part, leaf.count, leaf.nodes));
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Self, CertificateError> {
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line.trim()));
+        let error = |line: usize, message: &str| CertificateError::Parse { line, message: message.to_string() };
+
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            _ => return Err(error(1, "not a folds certificate")),
+        }
+
+        let mut field = |name: &str| -> Result<(usize, String), CertificateError> {
+            match lines.next() {
+                Some((line, content)) => match content.strip_prefix(name) {
+                    Some(value) if value.starts_with(' ') => Ok((line, value.trim().to_string())),
+                    _ => Err(error(line, &format!("expected `{}`", name))),
+                },
+                None => Err(error(0, &format!("missing `{}`", name))),
+            }
+        };
+
+        let (_, version) = field("version")?;
+        let (line, dims) = field("dims")?;
+        let dims: Vec<i32> = dims
+            .split_whitespace()
+            .map(|d| d.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| error(line, "invalid dimensions"))?;
+        let (line, parts) = field("parts")?;
+        let total_parts: usize = parts.parse().map_err(|_| error(line, "invalid part count"))?;
+        let (line, total) = field("total")?;
+        let total: i64 = total.parse().map_err(|_| error(line, "invalid total"))?;
+        let (line, root) = field("root")?;
+        let root = from_hex(&root).ok_or_else(|| error(line, "invalid root hash"))?;
+
+        let mut leaves = Vec::new();
+        for (line, content) in lines {
+            if content.is_empty() {
+                continue;
+            }
+            let values: Vec<&str> = content.split_whitespace().collect();
+            let parsed = match values.as_slice() {
+                ["leaf", part, count, nodes] => part
+                    .parse()
+                    .ok()
+                    .zip(count.parse().ok())
+                    .zip(nodes.parse().ok()),
+                _ => None,
+            };
+            let ((part, count), nodes) = parsed.ok_or_else(|| error(line, "expected `leaf <part> <count> <nodes>`"))?;
+            leaves.push(Leaf {
+                spec: UnitSpec { dims: dims.clone(), part, total_parts },
+                count,
+                nodes,
+                version: version.clone(),
+            });
+        }
+
+        Ok(Certificate { dims, total_parts, version, total, root, leaves })
+    }
+
+    /// Checks the structure of the certificate and recomputes `samples` randomly chosen leaves.
+    pub fn verify(&self, samples: usize, seed: u64) -> Result<Verification, CertificateError> {
+        let _span = tracing::debug_span!("verify", dims = ?self.dims, parts = self.total_parts).entered();
+        check_partition(&self.dims, self.total_parts)?;
+        check_coverage(&self.dims, self.total_parts, &self.leaves)?;
+
+        let hashes: Vec<Hash> = self.leaves.iter().map(Leaf::hash).collect();
+        if merkle_root(&hashes) != self.root {
+            return Err(CertificateError::RootMismatch);
+        }
+
+        let sum: i64 = self.leaves.iter().map(|leaf| leaf.count).sum();
+        if sum != self.total {
+            return Err(CertificateError::TotalMismatch { claimed: self.total, sum });
+        }
+
+        if samples > 0 && self.version != CODE_VERSION {
+            return Err(CertificateError::VersionMismatch {
+                certificate: self.version.clone(),
+                local: CODE_VERSION.to_string(),
+            });
+        }
+
+        // Partial Fisher-Yates so every part is sampled at most once
+        let mut order: Vec<usize> = (0..self.leaves.len()).collect();
+        let mut rng = SplitMix64::new(seed);
+        let samples = samples.min(order.len());
+        for i in 0..samples {
+            let j = i + rng.below((order.len() - i) as u64) as usize;
+            order.swap(i, j);
+        }
+        let mut sampled_parts: Vec<usize> = order[..samples].iter().map(|&i| self.leaves[i].spec.part).collect();
+        sampled_parts.sort_unstable();
+
+        sampled_parts
+            .par_iter()
+            .map(|&part| {
+                let claimed = &self.leaves[part];
+                let recomputed = Leaf::compute(&self.dims, part, self.total_parts);
+                if recomputed.count == claimed.count && recomputed.nodes == claimed.nodes {
+                    Ok(())
+                } else {
+                    Err(CertificateError::LeafMismatch {
+                        part,
+                        claimed: (claimed.count, claimed.nodes),
+                        recomputed: (recomputed.count, recomputed.nodes),
+                    })
+                }
+            })
+            .collect::<Result<Vec<()>, _>>()?;
+
+        Ok(Verification { total: self.total, sampled_parts })
+    }
+
+    pub fn inclusion_proof(&self, part: usize) -> Option<Vec<(Hash, bool)>> {
+        let index = self.leaves.iter().position(|leaf| leaf.spec.part == part)?;
+        let hashes: Vec<Hash> = self.leaves.iter().map(Leaf::hash).collect();
+        Some(inclusion_proof(&hashes, index))
+    }
+}
+
+/// Leaves must be sorted by part and name every part of the partition exactly once.
+fn check_coverage(dims: &[i32], total_parts: usize, leaves: &[Leaf]) -> Result<(), CertificateError> {
+    let mut expected = 0;
+    for leaf in leaves {
+        let part = leaf.spec.part;
+        if part >= total_parts || leaf.spec.total_parts != total_parts || leaf.spec.dims != dims {
+            return Err(CertificateError::PartOutOfRange(part));
+        }
+        if part < expected {
+            return Err(CertificateError::DuplicatePart(part));
+        }
+        if part > expected {
+            return Err(CertificateError::MissingPart(expected));
+        }
+        expected += 1;
+    }
+    if expected < total_parts {
+        return Err(CertificateError::MissingPart(expected));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certificate_round_trip_and_verify() {
+        let certificate = Certificate::compute(&[3, 3], 5).unwrap();
+        assert_eq!(certificate.total, 1368);
+        assert_eq!(certificate.leaves.len(), 5);
+
+        let parsed = Certificate::parse(&certificate.to_text()).unwrap();
+        assert_eq!(parsed, certificate);
+
+        let verification = parsed.verify(3, 42).unwrap();
+        assert_eq!(verification.total, 1368);
+        assert_eq!(verification.sampled_parts.len(), 3);
+    }
+
+    #[test]
+    fn test_tampered_certificate_is_rejected() {
+        let certificate = Certificate::compute(&[2, 4], 4).unwrap();
+
+        let mut wrong_count = certificate.clone();
+        wrong_count.leaves[1].count += 8;
+        assert_eq!(wrong_count.verify(0, 0), Err(CertificateError::RootMismatch));
+
+        let mut missing = certificate.clone();
+        missing.leaves.remove(2);
+        assert_eq!(missing.verify(0, 0), Err(CertificateError::MissingPart(2)));
+
+        let mut duplicate = certificate.clone();
+        duplicate.leaves[3] = duplicate.leaves[2].clone();
+        assert_eq!(duplicate.verify(0, 0), Err(CertificateError::DuplicatePart(2)));
+
+        // A consistent but fabricated certificate only fails when the bad leaf is recomputed
+        let mut leaves = certificate.leaves.clone();
+        leaves[0].count += 8;
+        let forged = Certificate::from_leaves(&[2, 4], 4, leaves).unwrap();
+        assert!(forged.verify(0, 0).is_ok());
+        assert!(matches!(forged.verify(4, 0), Err(CertificateError::LeafMismatch { part: 0, .. })));
+    }
+
+    #[test]
+    fn test_inclusion_proofs() {
+        let certificate = Certificate::compute(&[2, 3], 5).unwrap();
+        for leaf in &certificate.leaves {
+            let proof = certificate.inclusion_proof(leaf.spec.part).unwrap();
+            assert!(verify_inclusion(&leaf.hash(), &proof, &certificate.root));
+        }
+
+        let proof = certificate.inclusion_proof(0).unwrap();
+        assert!(!verify_inclusion(&certificate.leaves[1].hash(), &proof, &certificate.root));
+    }
+
+    #[test]
+    fn test_partition_must_fit_the_map() {
+        assert!(Certificate::compute(&[2, 2], 5).is_err());
+        assert!(Certificate::compute(&[2, 2], 0).is_err());
+        assert_eq!(Certificate::compute(&[4, 8, 8], 4), Err(CertificateError::InvalidDims(vec![4, 8, 8])));
+        assert_eq!(check_dims(&[7, 9]), Ok(63));
+        assert_eq!(check_dims(&[0, 100]), Ok(0));
+        assert!(check_dims(&[1 << 30, 1 << 30, 1 << 30]).is_err());
+        assert!(check_dims(&[]).is_err());
+    }
+}
@@ -1,31 +1,97 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
 use rayon::prelude::*;
 
-const MAX_N: usize = 64;
+use crate::polyomino::{LeafAdjacency, Polyomino};
+use crate::profile::{self, LevelProfile};
+use crate::rng::SplitMix64;
+
+pub(crate) const MAX_N: usize = 64;
+// Placements an adaptive search runs between checks for starving workers
+const SPLIT_CHECK_NODES: u64 = 1 << 14;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct CacheAlignedArrays {
-    big_p: [i32; MAX_N],
-    c: [[i32; MAX_N]; MAX_N],
-    d: Box<[i32; MAX_N * MAX_N * MAX_N]>, // Flattened 3D array
+    // Row i * stride + l holds d[i][l][m] for i in 0..=dim and l, m in 0..=n, with `stride` =
+    // n + 1. Rows are MAX_N wide so any leaf indexes them without a bounds check (see `leaf`).
+    d: Vec<[i32; MAX_N]>,
+    stride: usize,
+}
+
+// A leaf as an index into the MAX_N-sized tables. Leaves are below MAX_N already; reducing by
+// the power of two is free and lets the compiler drop the bounds checks in the inner loops.
+#[inline(always)]
+fn leaf(m: i32) -> usize {
+    m as usize % MAX_N
+}
+
+// Same for a position on the gap stack
+#[inline(always)]
+fn slot(j: i32) -> usize {
+    j as usize % (MAX_N * MAX_N)
 }
 
 pub struct StampFolder {
     pub count: i64,
+    pub nodes: u64,
     cache: CacheAlignedArrays,
     a: [i32; MAX_N],
     b: [i32; MAX_N],
     count_array: [i32; MAX_N],
     gapter: [i32; MAX_N],
     gap: [i32; MAX_N * MAX_N],
+    // Number of gaps generated at each level, for progress estimates
+    width: [i32; MAX_N],
+    // Search position, kept between calls to `resume`
+    p: Vec<i32>,
+    n: i32,
+    flag: bool,
+    res: i32,
+    mod_val: i32,
+    l: i32,
+    g: i32,
+    // Levels above `cut` are recorded as prefixes instead of searched; levels up to `floor` are fixed
+    cut: i32,
+    floor: i32,
+    prefixes: Vec<Vec<i32>>,
+    gap_mode: GapMode,
+    // Count the last level's gaps without placing them (off only to check against the full descent)
+    count_last_level: bool,
+    profile: Option<Vec<LevelProfile>>,
+    // For each leaf, smaller leaves that must lie above (true) or below (false) it; empty when free
+    order_constraints: Vec<Vec<(i32, bool)>>,
+}
+
+/// How `resume` finds the gaps for the next leaf. Both give the same counts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GapMode {
+    /// Candidate lists with per-leaf counters, filtered in a second pass.
+    #[default]
+    List,
+    /// One u64 candidate mask per dimension, intersected (n < 64).
+    Bitmask,
+}
+
+// Totals of an adaptive run, and the number of handed-off subtrees not yet picked up
+#[derive(Default)]
+struct AdaptiveTotals {
+    count: AtomicI64,
+    nodes: AtomicU64,
+    queued: AtomicUsize,
 }
 
-impl Default for CacheAlignedArrays {
+/// Result of running one `res/mod` part of the search.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PartStats {
+    pub count: i64,
+    /// Number of leaf placements made by the search.
+    pub nodes: u64,
+}
+
+impl Default for StampFolder {
     fn default() -> Self {
-        Self {
-            big_p: [0; MAX_N],
-            c: [[0; MAX_N]; MAX_N],
-            d: Box::new([0; MAX_N * MAX_N * MAX_N]),
-        }
+        Self::new()
     }
 }
 
@@ -34,145 +100,439 @@ impl StampFolder {
     pub fn new() -> Self {
         StampFolder {
             count: 0,
+            nodes: 0,
             cache: CacheAlignedArrays::default(),
             a: [0; MAX_N],
             b: [0; MAX_N],
             count_array: [0; MAX_N],
             gapter: [0; MAX_N],
             gap: [0; MAX_N * MAX_N],
+            width: [0; MAX_N],
+            p: Vec::new(),
+            n: 0,
+            flag: true,
+            res: 0,
+            mod_val: 0,
+            l: 0,
+            g: 0,
+            cut: 0,
+            floor: 0,
+            prefixes: Vec::new(),
+            gap_mode: GapMode::default(),
+            count_last_level: true,
+            profile: None,
+            order_constraints: Vec::new(),
         }
     }
 
-    #[inline(always)]
-    fn process(&mut self, n: i32) {
-        self.count += n as i64;
+    pub fn set_gap_mode(&mut self, mode: GapMode) {
+        self.gap_mode = mode;
     }
 
-    #[inline(always)]
-    fn calculate_big_p(&mut self, p: &[i32], dim: usize) {
-        self.cache.big_p[0] = 1;
-        for i in 1..=dim {
-            self.cache.big_p[i] = self.cache.big_p[i - 1].wrapping_mul(p[i - 1]);
-        }
+    /// Records per-level statistics from the next `start` on (see `profile::TreeProfile`).
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Vec::new());
     }
 
-    #[inline(always)]
-    fn calculate_c(&self, i: usize, m: i32, p: &[i32]) -> i32 {
-        (m - 1) / self.cache.big_p[i - 1] - ((m - 1) / self.cache.big_p[i]) * p[i - 1] + 1
+    /// Restricts the search to stacks where, for every `(u, above)` in `constraints[l]`, leaf
+    /// u < l lies above leaf l when `above` and below it otherwise (an empty list lifts all
+    /// restrictions). Kept across `start`. The list order is the stack order only without the
+    /// normalization flag, which is when this makes sense.
+    pub fn set_order_constraints(&mut self, constraints: Vec<Vec<(i32, bool)>>) {
+        self.order_constraints = constraints;
     }
 
-    #[inline(always)]
-    fn get_d(&self, i: usize, l: usize, m: usize) -> i32 {
-        self.cache.d[i * MAX_N * MAX_N + l * MAX_N + m]
+    pub fn take_profile(&mut self) -> Option<Vec<LevelProfile>> {
+        self.profile.take()
     }
 
     #[inline(always)]
-    fn set_d(&mut self, i: usize, l: usize, m: usize, value: i32) {
-        self.cache.d[i * MAX_N * MAX_N + l * MAX_N + m] = value;
+    fn process(&mut self, n: i32) {
+        self.count += n as i64;
     }
 
+    // d[i][l][..]
     #[inline(always)]
-    fn calculate_d(&self, i: usize, l: i32, m: i32, p: &[i32]) -> i32 {
-        let l_idx = l as usize;
-        let m_idx = m as usize;
-        let delta = self.cache.c[i][l_idx] - self.cache.c[i][m_idx];
-
-        if (delta & 1) == 0 {
-            if self.cache.c[i][m_idx] == 1 { m } else { m - self.cache.big_p[i - 1] }
-        } else if self.cache.c[i][m_idx] == p[i - 1] || m + self.cache.big_p[i - 1] > l {
-            m
-        } else {
-            m + self.cache.big_p[i - 1]
-        }
+    fn d_row(&self, i: usize, l: usize) -> &[i32; MAX_N] {
+        &self.cache.d[i * self.cache.stride + l]
     }
 
-    fn precalculate_arrays(&mut self, p: &[i32], n: i32, dim: usize) {
-        self.calculate_big_p(p, dim);
+    // d[i][l][m] is where the walk over the gaps for leaf l along axis i goes on from the gap
+    // below leaf m: across m's crease on the edge of the stack that l's crease to its lower
+    // neighbour lies on, or m itself when m has no such crease yet. Creases after even and after
+    // odd coordinates lie on opposite edges, so that is m's lower crease when m and l have the
+    // same parity and its upper one (placed only once the upper neighbour is) otherwise.
+    fn precalculate_arrays(&mut self, map: &LeafAdjacency) {
+        let n = map.n;
+        let dim = map.lower.len();
+        self.cache.stride = n as usize + 1;
+        self.cache.d.clear();
+        self.cache.d.resize((dim + 1) * self.cache.stride, [0; MAX_N]);
 
         for i in 1..=dim {
-            for m in 1..=n {
-                let m_idx = m as usize;
-                self.cache.c[i][m_idx] = self.calculate_c(i, m, p);
+            let (lower, upper, odd) = (&map.lower[i - 1], &map.upper[i - 1], &map.odd[i - 1]);
+            for l in 1..=n as usize {
+                let row = &mut self.cache.d[i * self.cache.stride + l];
+                for m in 1..=l {
+                    row[m] = if odd[l] == odd[m] {
+                        if lower[m] == 0 { m as i32 } else { lower[m] }
+                    } else if upper[m] == 0 || upper[m] > l as i32 {
+                        m as i32
+                    } else {
+                        upper[m]
+                    };
+                }
             }
         }
+    }
 
-        for i in 1..=dim {
-            for l in 1..=n {
-                let l_idx = l as usize;
-                for m in 1..=l {
-                    let m_idx = m as usize;
-                    let d_value = self.calculate_d(i, l, m, p);
-                    self.set_d(i, l_idx, m_idx, d_value);
-                }
+    // Every gap for the last leaf completes a folding, so count them instead of placing each one.
+    // Placing leaf n in gap m leaves b[0] alone unless m == 0, which decides the flag check the
+    // completed folding would face. Nodes still count the skipped placements. Returns the
+    // emptied gap stack top.
+    #[inline(always)]
+    fn count_completions(&mut self, n: i32, g: i32, flag: bool) -> i32 {
+        let start = self.gapter[(n - 1) as usize];
+        for j in start..g {
+            let top = if self.gap[j as usize] == 0 { n } else { self.b[0] };
+            if !flag || top == 1 {
+                self.process(n);
             }
         }
+        self.nodes += (g - start) as u64;
+        start
+    }
+
+    // The number of dimensions: DIM in the specialized copies of the search, `dim` when DIM is 0
+    #[inline(always)]
+    fn dims<const DIM: usize>(dim: usize) -> usize {
+        if DIM == 0 { dim } else { DIM }
     }
 
     #[inline(always)]
-    fn process_gaps(&mut self, l: i32, g: &mut i32, gg: &mut i32, dd: &mut i32, dim: usize, res: i32, mod_val: i32) {
+    #[allow(clippy::too_many_arguments)]
+    fn process_gaps<const DIM: usize>(&mut self, l: i32, g: &mut i32, gg: &mut i32, dd: &mut i32, dim: usize, res: i32, mod_val: i32) {
+        let dim = Self::dims::<DIM>(dim);
+        let l_idx = l as usize;
+        let filter = mod_val != 0 && l == mod_val;
+        let stride = self.cache.stride;
+        let (d, b, gap, count_array) = (&self.cache.d, &self.b, &mut self.gap, &mut self.count_array);
+        if DIM == 2 {
+            let (row1, row2) = (&d[stride + l_idx], &d[2 * stride + l_idx]);
+            let (mut m1, mut m2) = (row1[leaf(l)], row2[leaf(l)]);
+            if m1 != l && m2 != l {
+                // Each walk is a chain of dependent loads, so run both at once. The first axis
+                // lists its gaps in the order the walk below would, the second only marks its own
+                // (neither walk meets a leaf twice); the gaps are those on both.
+                let mut marked = 0u64;
+                while m1 != l || m2 != l {
+                    if m1 != l {
+                        if !filter || m1 % mod_val == res {
+                            gap[slot(*gg)] = m1;
+                            *gg += 1;
+                        }
+                        m1 = row1[leaf(b[leaf(m1)])];
+                    }
+                    if m2 != l {
+                        marked |= 1 << leaf(m2);
+                        m2 = row2[leaf(b[leaf(m2)])];
+                    }
+                }
+                for j in *g..*gg {
+                    let gap_j = gap[slot(j)];
+                    gap[slot(*g)] = gap_j;
+                    *g += (marked >> leaf(gap_j) & 1) as i32;
+                }
+                return;
+            }
+        }
         for i in 1..=dim {
-            let l_idx = l as usize;
-            if self.get_d(i, l_idx, l_idx) == l {
+            let row = &d[i * stride + l_idx];
+            let mut m = row[leaf(l)];
+            if m == l {
                 *dd += 1;
                 continue;
             }
 
-            let mut m = self.get_d(i, l_idx, l_idx);
             while m != l {
-                if mod_val == 0 || l != mod_val || m % mod_val == res {
-                    self.gap[*gg as usize] = m;
-                    self.count_array[m as usize] += 1;
-                    if self.count_array[m as usize] == 1 {
-                        *gg += 1;
-                    }
+                if !filter || m % mod_val == res {
+                    gap[slot(*gg)] = m;
+                    count_array[leaf(m)] += 1;
+                    *gg += (count_array[leaf(m)] == 1) as i32;
                 }
-                m = self.get_d(i, l_idx, self.b[m as usize] as usize);
+                m = row[leaf(b[leaf(m)])];
             }
         }
 
         if *dd == dim as i32 {
             for m in 0..l {
-                self.gap[*gg as usize] = m;
+                gap[slot(*gg)] = m;
                 *gg += 1;
             }
         }
 
         let g_start = *g;
         for j in g_start..*gg {
-            let gap_j = self.gap[j as usize];
-            self.gap[*g as usize] = gap_j;
-            *g += (self.count_array[gap_j as usize] == (dim as i32 - *dd)) as i32;
-            self.count_array[gap_j as usize] = 0;
+            let gap_j = gap[slot(j)];
+            gap[slot(*g)] = gap_j;
+            *g += (count_array[leaf(gap_j)] == (dim as i32 - *dd)) as i32;
+            count_array[leaf(gap_j)] = 0;
+        }
+    }
+
+    // Same gaps as process_gaps: leaf positions that every constrained dimension offers, or all
+    // of 0..l when no dimension constrains leaf l. Pushed in increasing order.
+    #[inline(always)]
+    fn process_gaps_bitmask<const DIM: usize>(&mut self, l: i32, g: &mut i32, dim: usize, res: i32, mod_val: i32) {
+        let dim = Self::dims::<DIM>(dim);
+        let l_idx = l as usize;
+        let filter = mod_val != 0 && l == mod_val;
+        let mut mask = (1u64 << l) - 1;
+        for i in 1..=dim {
+            let row = self.d_row(i, l_idx);
+            let mut m = row[leaf(l)];
+            if m == l {
+                continue;
+            }
+
+            let mut candidates = 0u64;
+            while m != l {
+                if !filter || m % mod_val == res {
+                    candidates |= 1 << m;
+                }
+                m = row[leaf(self.b[leaf(m)])];
+            }
+            mask &= candidates;
+            if mask == 0 {
+                return;
+            }
+        }
+
+        while mask != 0 {
+            self.gap[slot(*g)] = mask.trailing_zeros() as i32;
+            *g += 1;
+            mask &= mask - 1;
         }
     }
 
     pub fn foldings(&mut self, p: &[i32], flag: bool, res: i32, mod_val: i32) {
-        let n: i32 = p.iter().product();
+        self.start(p, flag, res, mod_val);
+        self.resume(u64::MAX);
+    }
+
+    /// Prepares a search without running it; `count` and `nodes` keep accumulating.
+    pub fn start(&mut self, p: &[i32], flag: bool, res: i32, mod_val: i32) {
+        self.start_map(&Polyomino::rectangle(p), flag, res, mod_val);
+    }
+
+    /// `start` for a map of any shape within the box `map.dims`.
+    pub fn start_map(&mut self, map: &Polyomino, flag: bool, res: i32, mod_val: i32) {
+        let adjacency = map.adjacency();
+        let n = adjacency.n;
         if n as usize >= MAX_N {
             panic!("Dimension too large");
         }
+        self.precalculate_arrays(&adjacency);
+
+        if let Some(levels) = &mut self.profile {
+            if levels.len() != n as usize {
+                *levels = profile::new_levels(n as usize);
+            }
+        }
+
+        self.p = map.dims.clone();
+        self.n = n;
+        self.flag = flag;
+        self.res = res;
+        self.mod_val = mod_val;
+        self.g = 0;
+        self.l = 1;
+        self.cut = n;
+        self.floor = 0;
+    }
+
+    /// Prepares a search of the subtree below `prefix`, the gap chosen for each of leaves 1, 2, ...
+    /// (as produced by `subtree_prefixes`).
+    pub fn start_subtree(&mut self, p: &[i32], prefix: &[i32]) {
+        self.start(p, true, 0, 0);
+        self.a[0] = 0;
+        self.b[0] = 0;
+        for (k, &m) in prefix.iter().enumerate() {
+            let l = k + 1;
+            let b_m = self.b[m as usize];
+            self.a[l] = m;
+            self.b[l] = b_m;
+            self.b[m as usize] = l as i32;
+            self.a[b_m as usize] = l as i32;
+            self.gapter[l] = 0;
+        }
+        self.floor = prefix.len() as i32;
+        self.l = self.floor + 1;
+    }
+
+    // The gap each placed leaf went into is the nearest smaller leaf before it in the stack,
+    // since everything inserted later is larger. Covers leaves 1..l.
+    fn placed_gaps(&self, l: i32) -> Vec<i32> {
+        let mut order = vec![0];
+        let mut leaf = self.b[0];
+        while leaf != 0 {
+            order.push(leaf);
+            leaf = self.b[leaf as usize];
+        }
+
+        (1..l)
+            .map(|k| {
+                let position = order.iter().position(|&leaf| leaf == k).unwrap();
+                *order[..position].iter().rev().find(|&&leaf| leaf < k).unwrap()
+            })
+            .collect()
+    }
+
+    fn record_prefix(&mut self, l: i32) {
+        let prefix = self.placed_gaps(l);
+        self.prefixes.push(prefix);
+    }
+
+    /// Takes the untried gaps at the shallowest level that has any out of the started search and
+    /// returns them as subtree prefixes (for `start_subtree`), which together with what is left
+    /// here cover the original search. Only unfiltered searches (`flag`, no `res/mod`) split.
+    pub fn split_off(&mut self) -> Vec<Vec<i32>> {
+        if !self.flag || self.mod_val != 0 || self.l <= self.floor {
+            return Vec::new();
+        }
+        let Some(k) = (self.floor + 1..self.l).find(|&k| self.gapter[k as usize] > self.gapter[(k - 1) as usize]) else {
+            return Vec::new();
+        };
+
+        let (low, high) = (self.gapter[(k - 1) as usize] as usize, self.gapter[k as usize] as usize);
+        let placed = self.placed_gaps(k);
+        let prefixes: Vec<Vec<i32>> = self.gap[low..high]
+            .iter()
+            .map(|&m| placed.iter().copied().chain([m]).collect())
+            .collect();
+
+        // Close the hole: everything from leaf k's own gap up to the top of the stack moves down
+        let shift = (high - low) as i32;
+        self.gap.copy_within(high..=self.g as usize, low);
+        for j in k..self.l {
+            self.gapter[j as usize] -= shift;
+        }
+        self.g -= shift;
+        // Each handed-off prefix stands for the placement of leaf k this search would have made
+        self.nodes += prefixes.len() as u64;
+        prefixes
+    }
+
+    // Drops the gaps that would put leaf l on the wrong side of a constrained smaller leaf.
+    // Leaf l goes right below gap m, so u ends up above it exactly when u is m or above m.
+    fn filter_order_constraints(&mut self, l: i32, g: i32) -> i32 {
+        let wanted = &self.order_constraints[l as usize];
+        let base = self.gapter[(l - 1) as usize];
+        if wanted.is_empty() {
+            return g;
+        }
+
+        let mut position = [0; MAX_N];
+        let (mut leaf, mut depth) = (self.b[0], 1);
+        while leaf != 0 {
+            position[leaf as usize] = depth;
+            depth += 1;
+            leaf = self.b[leaf as usize];
+        }
+
+        let mut kept = base;
+        for j in base..g {
+            let m = self.gap[j as usize];
+            if wanted.iter().all(|&(u, above)| (position[u as usize] <= position[m as usize]) == above) {
+                self.gap[kept as usize] = m;
+                kept += 1;
+            }
+        }
+        kept
+    }
 
-        let dim = p.len();
-        self.precalculate_arrays(p, n, dim);
+    /// Runs the started search for at most `max_nodes` more placements.
+    /// Returns true once the search is exhausted.
+    pub fn resume(&mut self, max_nodes: u64) -> bool {
+        // Specialized copies for the common dimensionalities, so the per-dimension loops unroll
+        match self.p.len() {
+            _ if self.profile.is_some() => self.resume_dim::<0, true, _>(max_nodes, &mut |_| {}),
+            1 => self.resume_dim::<1, false, _>(max_nodes, &mut |_| {}),
+            2 => self.resume_dim::<2, false, _>(max_nodes, &mut |_| {}),
+            3 => self.resume_dim::<3, false, _>(max_nodes, &mut |_| {}),
+            4 => self.resume_dim::<4, false, _>(max_nodes, &mut |_| {}),
+            _ => self.resume_dim::<0, false, _>(max_nodes, &mut |_| {}),
+        }
+    }
 
-        let mut g = 0;
-        let mut l = 1;
+    /// Runs the started search to the end, calling `visit` with every stack it completes (leaves
+    /// from the top, leaf 1 first when normalizing), in search order.
+    pub fn visit_stacks(&mut self, mut visit: impl FnMut(&[i32])) {
+        let mut stack = Vec::with_capacity(self.n as usize);
+        // Completions have to be placed to be seen
+        let count_last_level = std::mem::replace(&mut self.count_last_level, false);
+        self.resume_dim::<0, false, _>(u64::MAX, &mut |b: &[i32]| {
+            stack.clear();
+            let mut leaf = b[0];
+            while leaf != 0 {
+                stack.push(leaf);
+                leaf = b[leaf as usize];
+            }
+            visit(&stack);
+        });
+        self.count_last_level = count_last_level;
+    }
+
+    // `complete` sees the b links of every counted folding that is placed in full
+    fn resume_dim<const DIM: usize, const PROFILE: bool, F: FnMut(&[i32])>(&mut self, max_nodes: u64, complete: &mut F) -> bool {
+        let n = self.n;
+        let dim = Self::dims::<DIM>(self.p.len());
+        let (flag, res, mod_val) = (self.flag, self.res, self.mod_val);
+        let (cut, floor) = (self.cut, self.floor);
+        let stop_at = self.nodes.saturating_add(max_nodes);
 
-        while l > 0 {
+        let mut g = self.g;
+        let mut l = self.l;
+
+        while l > floor {
             if !flag || l <= 1 || self.b[0] == 1 {
-                if l > n {
-                    self.process(n);
+                if l > cut {
+                    if l > n {
+                        self.process(n);
+                        complete(&self.b);
+                    } else {
+                        self.record_prefix(l);
+                    }
                 } else {
-                    let mut dd = 0;
+                    let started = PROFILE.then(Instant::now);
                     let mut gg = self.gapter[(l - 1) as usize];
                     g = gg;
-                    self.process_gaps(l, &mut g, &mut gg, &mut dd, dim, res, mod_val);
+                    match self.gap_mode {
+                        GapMode::List => self.process_gaps::<DIM>(l, &mut g, &mut gg, &mut 0, dim, res, mod_val),
+                        GapMode::Bitmask => self.process_gaps_bitmask::<DIM>(l, &mut g, dim, res, mod_val),
+                    }
+                    if !self.order_constraints.is_empty() {
+                        g = self.filter_order_constraints(l, g);
+                    }
+                    let width = g - self.gapter[(l - 1) as usize];
+                    self.width[l as usize] = width;
+                    if let (Some(levels), Some(started)) = (&mut self.profile, started) {
+                        profile::record_expansion(levels, l, width, started);
+                    }
+                    if l == n && self.count_last_level {
+                        g = self.count_completions(n, g, flag);
+                        if let (true, Some(levels)) = (PROFILE, &mut self.profile) {
+                            levels[(n - 1) as usize].nodes += width as u64;
+                        }
+                    }
                 }
+            } else if let (true, Some(levels)) = (PROFILE && l <= n, &mut self.profile) {
+                levels[(l - 1) as usize].pruned += 1;
             }
 
-            while l > 0 && g == self.gapter[(l - 1) as usize] {
+            while l > floor && g == self.gapter[(l - 1) as usize] {
                 l -= 1;
-                if l > 0 {
+                if l > floor {
                     let a_l = self.a[l as usize];
                     let b_l = self.b[l as usize];
                     self.b[a_l as usize] = b_l;
@@ -180,7 +540,7 @@ impl StampFolder {
                 }
             }
 
-            if l > 0 {
+            if l > floor {
                 g -= 1;
                 let gap_g = self.gap[g as usize];
                 self.a[l as usize] = gap_g;
@@ -189,15 +549,98 @@ impl StampFolder {
                 self.b[gap_g as usize] = l;
                 self.a[b_gap as usize] = l;
                 self.gapter[l as usize] = g;
+                self.nodes += 1;
+                if let (true, Some(levels)) = (PROFILE, &mut self.profile) {
+                    levels[(l - 1) as usize].nodes += 1;
+                }
                 l += 1;
+
+                if self.nodes >= stop_at {
+                    break;
+                }
+            }
+        }
+
+        self.g = g;
+        self.l = l;
+        l <= floor
+    }
+
+    /// Knuth's estimator: follows one random path down from the started position and returns
+    /// the sum of the products of the gap counts met on the way, which averages to the number
+    /// of placements below it. The position is restored afterwards.
+    pub(crate) fn probe(&mut self, rng: &mut SplitMix64) -> f64 {
+        let n = self.n;
+        let dim = self.p.len();
+        let (res, mod_val) = (self.res, self.mod_val);
+        let first = self.l;
+        let base = self.gapter[(first - 1).max(0) as usize];
+
+        let mut estimate = 0.0;
+        let mut paths = 1.0;
+        let mut l = first;
+        while l <= n && (!self.flag || l <= 1 || self.b[0] == 1) {
+            let (mut g, mut gg) = (base, base);
+            self.process_gaps::<0>(l, &mut g, &mut gg, &mut 0, dim, res, mod_val);
+            let width = g - base;
+            if width == 0 {
+                break;
             }
+            paths *= width as f64;
+            estimate += paths;
+
+            let gap = self.gap[(base + rng.below(width as u64) as i32) as usize];
+            let b_gap = self.b[gap as usize];
+            self.a[l as usize] = gap;
+            self.b[l as usize] = b_gap;
+            self.b[gap as usize] = l;
+            self.a[b_gap as usize] = l;
+            l += 1;
+        }
+
+        for k in (first..l).rev() {
+            let (a_k, b_k) = (self.a[k as usize], self.b[k as usize]);
+            self.b[a_k as usize] = b_k;
+            self.a[b_k as usize] = a_k;
+        }
+        estimate
+    }
+
+    /// Gaps the started search tries for its next leaf, in the order it tries them. Empty once
+    /// every leaf is placed or the position fails the normalization check.
+    pub(crate) fn next_gaps(&mut self) -> Vec<i32> {
+        let n = self.n;
+        let (l, dim) = (self.l, self.p.len());
+        if l > n || (self.flag && l > 1 && self.b[0] != 1) {
+            return Vec::new();
         }
+        let base = self.gapter[(l - 1) as usize];
+        let (mut g, mut gg) = (base, base);
+        self.process_gaps::<0>(l, &mut g, &mut gg, &mut 0, dim, self.res, self.mod_val);
+        self.gap[base as usize..g as usize].iter().rev().copied().collect()
+    }
+
+    /// Estimated fraction of the started search that is finished, from the first few levels.
+    pub fn progress(&self) -> f64 {
+        if self.l <= 0 {
+            return 1.0;
+        }
+
+        let mut done = 0.0;
+        let mut weight = 1.0;
+        for k in 1..(self.l as usize).min(12) {
+            let width = self.width[k].max(1) as f64;
+            let remaining = (self.gapter[k] - self.gapter[k - 1]) as f64;
+            done += weight * (width - remaining - 1.0) / width;
+            weight /= width;
+        }
+        done
     }
 
     // Helper function to calculate sequence for specific dimensions
     pub fn calculate_sequence(dimensions: &[i32]) -> i64 {
         // Special case: if any dimension is 0, return 1
-        if dimensions.iter().any(|&d| d == 0) {
+        if dimensions.contains(&0) {
             return 1;
         }
 
@@ -206,21 +649,101 @@ impl StampFolder {
         folder.count
     }
 
-    // Helper function to calculate sequence for specific dimensions and modulo parameters
-    pub fn calculate_sequence_part(dimensions: &[i32], part: usize, total_parts: usize) -> i64 {
-        // Special case: if any dimension is 0 and this is part 0, return 1
-        if dimensions.iter().any(|&d| d == 0) {
-            return if part == 0 { 1 } else { 0 };
+    /// `calculate_sequence` with a chosen gap computation, on one thread.
+    pub fn calculate_sequence_with(dimensions: &[i32], mode: GapMode) -> i64 {
+        if dimensions.contains(&0) {
+            return 1;
         }
 
         let mut folder = StampFolder::new();
+        folder.set_gap_mode(mode);
+        folder.foldings(dimensions, true, 0, 0);
+        folder.count
+    }
+
+    /// The `d` table for `dimensions`, compacted to `(dim + 1) × (n + 1) × (n + 1)` entries
+    /// (index `(i * (n + 1) + l) * (n + 1) + m`), for backends that search on their own.
+    pub fn connection_table(dimensions: &[i32]) -> Vec<i32> {
+        let mut folder = StampFolder::new();
+        folder.precalculate_arrays(&Polyomino::rectangle(dimensions).adjacency());
+        let stride = folder.cache.stride;
+        folder.cache.d.iter().flat_map(|row| &row[..stride]).copied().collect()
+    }
+
+    /// Gap choices of the first `depth` leaves for every subtree alive at that depth, in search order.
+    /// Together the subtrees cover the whole (unpartitioned) search.
+    pub fn subtree_prefixes(dimensions: &[i32], depth: usize) -> Vec<Vec<i32>> {
         let n = dimensions.iter().product::<i32>();
+        if dimensions.contains(&0) || depth == 0 {
+            return vec![Vec::new()];
+        }
 
-        // Generate a sequence of indices from part to n, stepping by total_parts
-        for i in (part..n as usize).step_by(total_parts) {
-            folder.foldings(dimensions, true, i as i32, total_parts as i32);
+        let mut folder = StampFolder::new();
+        folder.start(dimensions, true, 0, 0);
+        folder.cut = depth.min(n as usize - 1) as i32;
+        folder.resume(u64::MAX);
+        folder.prefixes
+    }
+
+    /// Counts the foldings below one prefix from `subtree_prefixes`.
+    pub fn count_subtree(dimensions: &[i32], prefix: &[i32]) -> PartStats {
+        if dimensions.contains(&0) {
+            return PartStats { count: 1, nodes: 0 };
         }
-        folder.count
+
+        let _span = tracing::trace_span!("unit", ?prefix).entered();
+        let mut folder = StampFolder::new();
+        folder.start_subtree(dimensions, prefix);
+        folder.resume(u64::MAX);
+        tracing::trace!(count = folder.count, nodes = folder.nodes, "unit finished");
+        PartStats { count: folder.count, nodes: folder.nodes }
+    }
+
+    // Helper function to calculate sequence for specific dimensions and modulo parameters
+    pub fn calculate_sequence_part(dimensions: &[i32], part: usize, total_parts: usize) -> i64 {
+        Self::calculate_part_stats(dimensions, part, total_parts).count
+    }
+
+    // Same as calculate_sequence_part, but also reports how many nodes the search visited
+    pub fn calculate_part_stats(dimensions: &[i32], part: usize, total_parts: usize) -> PartStats {
+        let _span = tracing::debug_span!("part", ?dimensions, part, total_parts).entered();
+        let mut search = PartSearch::new(dimensions, part, total_parts);
+        search.step(u64::MAX);
+        let stats = search.stats();
+        tracing::debug!(count = stats.count, nodes = stats.nodes, "part finished");
+        stats
+    }
+
+    /// Whole count on the current rayon pool. Instead of a fixed split, a search hands untried
+    /// branches near the root to new tasks whenever no task is waiting for a worker, so the run
+    /// time follows the total work rather than the heaviest part.
+    pub fn calculate_adaptive_stats(dimensions: &[i32]) -> PartStats {
+        if dimensions.contains(&0) {
+            return PartStats { count: 1, nodes: 0 };
+        }
+
+        let shared = AdaptiveTotals::default();
+        rayon::scope(|scope| Self::search_adaptive(scope, dimensions, Vec::new(), &shared));
+        PartStats { count: shared.count.into_inner(), nodes: shared.nodes.into_inner() }
+    }
+
+    fn search_adaptive<'s>(scope: &rayon::Scope<'s>, dimensions: &'s [i32], prefix: Vec<i32>, shared: &'s AdaptiveTotals) {
+        let mut folder = StampFolder::new();
+        folder.start_subtree(dimensions, &prefix);
+        let share = rayon::current_num_threads() > 1;
+        while !folder.resume(SPLIT_CHECK_NODES) {
+            if share && shared.queued.load(Ordering::Relaxed) == 0 {
+                for prefix in folder.split_off() {
+                    shared.queued.fetch_add(1, Ordering::Relaxed);
+                    scope.spawn(move |scope| {
+                        shared.queued.fetch_sub(1, Ordering::Relaxed);
+                        Self::search_adaptive(scope, dimensions, prefix, shared);
+                    });
+                }
+            }
+        }
+        shared.count.fetch_add(folder.count, Ordering::Relaxed);
+        shared.nodes.fetch_add(folder.nodes, Ordering::Relaxed);
     }
 
     // Helper function to calculate complete sequence using parallel processing
@@ -231,20 +754,269 @@ impl StampFolder {
             return Self::calculate_sequence(dimensions);
         }
 
-        // Use parallel iterator for larger dimensions
-        (0..num_threads).into_par_iter()
-            .map(|part| Self::calculate_sequence_part(dimensions, part, num_threads))
+        // Use parallel iterator for larger dimensions; the res/mod split needs parts <= n
+        let parts = num_threads.clamp(1, n as usize);
+        (0..parts).into_par_iter()
+            .map(|part| Self::calculate_sequence_part(dimensions, part, parts))
             .sum()
     }
 }
 
+/// A `res/mod` part that can be run in slices, checkpointed and resumed.
+pub struct PartSearch {
+    dims: Vec<i32>,
+    part: usize,
+    total_parts: usize,
+    // Residue being searched, or the next one to start
+    residue: usize,
+    in_progress: bool,
+    folder: StampFolder,
+}
+
+pub const CHECKPOINT_HEADER: &str = "folds-checkpoint v1";
+
+impl PartSearch {
+    pub fn new(dimensions: &[i32], part: usize, total_parts: usize) -> Self {
+        let mut folder = StampFolder::new();
+        // Special case: if any dimension is 0 and this is part 0, the count is 1
+        if dimensions.contains(&0) && part == 0 {
+            folder.count = 1;
+        }
+
+        PartSearch {
+            dims: dimensions.to_vec(),
+            part,
+            total_parts,
+            residue: part,
+            in_progress: false,
+            folder,
+        }
+    }
+
+    fn n(&self) -> usize {
+        self.dims.iter().product::<i32>().max(0) as usize
+    }
+
+    // Residues run are part, part + total_parts, ... below this bound. A single part skips the
+    // residue filter altogether: at l = 1 every dimension is unconstrained and all gaps pass
+    // unfiltered, so residue 0 alone already covers the whole search.
+    fn end(&self) -> usize {
+        if self.total_parts == 1 { self.n().min(1) } else { self.n() }
+    }
+
+    fn mod_val(&self) -> i32 {
+        if self.total_parts == 1 { 0 } else { self.total_parts as i32 }
+    }
+
+    pub fn set_gap_mode(&mut self, mode: GapMode) {
+        self.folder.set_gap_mode(mode);
+    }
+
+    pub fn enable_profile(&mut self) {
+        self.folder.enable_profile();
+    }
+
+    pub fn take_profile(&mut self) -> Option<Vec<LevelProfile>> {
+        self.folder.take_profile()
+    }
+
+    /// The `(dims, part, total_parts)` this search covers.
+    pub fn unit(&self) -> (&[i32], usize, usize) {
+        (&self.dims, self.part, self.total_parts)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !self.in_progress && self.residue >= self.end()
+    }
+
+    /// Runs for at most `max_nodes` placements; returns true once the whole part is done.
+    pub fn step(&mut self, max_nodes: u64) -> bool {
+        let stop_at = self.folder.nodes.saturating_add(max_nodes);
+        // Generate a sequence of indices from part to n, stepping by total_parts
+        while !self.is_finished() && self.folder.nodes < stop_at {
+            if !self.in_progress {
+                self.folder.start(&self.dims, true, self.residue as i32, self.mod_val());
+                self.in_progress = true;
+            }
+            if self.folder.resume(stop_at - self.folder.nodes) {
+                tracing::trace!(residue = self.residue, count = self.folder.count, "residue finished");
+                self.in_progress = false;
+                self.residue += self.total_parts;
+            }
+        }
+        self.is_finished()
+    }
+
+    pub fn stats(&self) -> PartStats {
+        PartStats { count: self.folder.count, nodes: self.folder.nodes }
+    }
+
+    /// Knuth's estimate of the placements the whole part makes, from `probes` random descents
+    /// below each of its residues. Only meaningful before the search is stepped.
+    pub(crate) fn estimate_nodes(&mut self, probes: usize, rng: &mut SplitMix64) -> f64 {
+        let (end, mod_val, probes) = (self.end(), self.mod_val(), probes.max(1));
+        let mut estimate = 0.0;
+        for residue in (self.part..end).step_by(self.total_parts) {
+            self.folder.start(&self.dims, true, residue as i32, mod_val);
+            estimate += (0..probes).map(|_| self.folder.probe(rng)).sum::<f64>() / probes as f64;
+        }
+        estimate
+    }
+
+    pub fn fraction_done(&self) -> f64 {
+        let end = self.end();
+        if self.is_finished() || self.part >= end {
+            return 1.0;
+        }
+
+        let residues = (end - self.part).div_ceil(self.total_parts);
+        let finished = (self.residue - self.part) / self.total_parts;
+        let current = if self.in_progress { self.folder.progress() } else { 0.0 };
+        ((finished as f64 + current) / residues as f64).min(1.0)
+    }
+
+    pub fn checkpoint(&self) -> String {
+        let f = &self.folder;
+        let join = |values: &[i32]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(" ");
+        let n = self.n();
+        let l = f.l.max(0) as usize;
+
+        let mut text = format!(
+            "{}\ndims {}\npart {}\nparts {}\nresidue {}\nin_progress {}\ncount {}\nnodes {}\n",
+            CHECKPOINT_HEADER,
+            join(&self.dims),
+            self.part,
+            self.total_parts,
+            self.residue,
+            self.in_progress as u8,
+            f.count,
+            f.nodes
+        );
+        if self.in_progress {
+            text.push_str(&format!("l {}\ng {}\n", f.l, f.g));
+            text.push_str(&format!("a {}\n", join(&f.a[..=n])));
+            text.push_str(&format!("b {}\n", join(&f.b[..=n])));
+            text.push_str(&format!("gapter {}\n", join(&f.gapter[..l])));
+            text.push_str(&format!("width {}\n", join(&f.width[..l])));
+            text.push_str(&format!("gap {}\n", join(&f.gap[..f.g as usize])));
+        }
+        text
+    }
+
+    pub fn restore(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        if lines.next() != Some(CHECKPOINT_HEADER) {
+            return Err("not a folds checkpoint".to_string());
+        }
+
+        let mut fields = std::collections::HashMap::new();
+        for line in lines {
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            fields.insert(name, value);
+        }
+        let values = |name: &str| -> Result<Vec<i64>, String> {
+            let value = fields.get(name).ok_or_else(|| format!("checkpoint is missing `{}`", name))?;
+            value
+                .split_whitespace()
+                .map(|v| v.parse().map_err(|_| format!("invalid `{}` in checkpoint", name)))
+                .collect()
+        };
+        let single = |name: &str| -> Result<i64, String> {
+            match values(name)?.as_slice() {
+                [value] => Ok(*value),
+                _ => Err(format!("`{}` must be a single value", name)),
+            }
+        };
+
+        let dims = values("dims")?;
+        let (part, total_parts, residue) = (single("part")?, single("parts")?, single("residue")?);
+        // Leaves of the map, None when the search could never take it on
+        let leaves = dims.iter().try_fold(1i64, |n, &d| if d < 0 { None } else { n.checked_mul(d) });
+        let Some(leaves) = leaves.filter(|&n| n < MAX_N as i64 && !dims.is_empty()) else {
+            return Err("invalid `dims` in checkpoint".to_string());
+        };
+        let parts_valid = total_parts >= 1 && total_parts <= leaves.max(1) && (0..total_parts).contains(&part);
+        if !parts_valid || residue < part || (residue - part) % total_parts != 0 {
+            return Err("checkpoint part is outside the partition".to_string());
+        }
+        let dims: Vec<i32> = dims.iter().map(|&d| d as i32).collect();
+        let mut search = PartSearch::new(&dims, part as usize, total_parts as usize);
+        search.residue = residue as usize;
+        search.in_progress = single("in_progress")? != 0;
+        let (count, nodes) = (single("count")?, single("nodes")?);
+        if count < 0 || nodes < 0 {
+            return Err(format!("`{}` has the wrong shape", if count < 0 { "count" } else { "nodes" }));
+        }
+        search.folder.count = count;
+        search.folder.nodes = nodes as u64;
+
+        if search.in_progress {
+            let n = search.n();
+            if search.residue >= search.end() {
+                return Err("checkpoint position is outside the map".to_string());
+            }
+            search.folder.start(&dims, true, search.residue as i32, search.mod_val());
+
+            let (l, g) = (single("l")?, single("g")?);
+            let restore = |target: &mut [i32], name: &str, len: usize, max: usize| -> Result<(), String> {
+                let loaded = values(name)?;
+                if loaded.len() != len || loaded.iter().any(|&v| v < 0 || v as usize > max) {
+                    return Err(format!("`{}` has the wrong shape", name));
+                }
+                for (slot, value) in target.iter_mut().zip(loaded) {
+                    *slot = value as i32;
+                }
+                Ok(())
+            };
+            if l < 1 || l as usize > n + 1 || g < 0 || g as usize > MAX_N * MAX_N {
+                return Err("checkpoint position is outside the map".to_string());
+            }
+            let (l, g) = (l as usize, g as usize);
+
+            let f = &mut search.folder;
+            restore(&mut f.a, "a", n + 1, n)?;
+            restore(&mut f.b, "b", n + 1, n)?;
+            restore(&mut f.gapter, "gapter", l, g)?;
+            restore(&mut f.width, "width", l, n)?;
+            restore(&mut f.gap, "gap", g, n)?;
+
+            // Leaves 0..l form a single ring through the b links, with a as its inverse
+            let inverse = (0..l).all(|k| (f.b[k] as usize) < l && f.a[f.b[k] as usize] as usize == k);
+            let ring = inverse && {
+                let (mut leaf, mut visited) = (f.b[0] as usize, 1);
+                while leaf != 0 {
+                    leaf = f.b[leaf] as usize;
+                    visited += 1;
+                }
+                visited == l
+            };
+            if !ring {
+                return Err("checkpoint links do not form a stack".to_string());
+            }
+            // Level k keeps at most k - 1 of its gaps on the stack, each a position among 0..k
+            let stack_valid = f.gapter[0] == 0
+                && f.gapter[l - 1] as usize == g
+                && (1..l).all(|k| {
+                    let (from, to) = (f.gapter[k - 1] as usize, f.gapter[k] as usize);
+                    from <= to && to - from < k && f.gap[from..to].iter().all(|&m| (m as usize) < k)
+                });
+            if !stack_valid {
+                return Err("checkpoint gap stack is inconsistent".to_string());
+            }
+            f.l = l as i32;
+            f.g = g as i32;
+        }
+        Ok(search)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_sequence_n_2() {
-        let expected = vec![
+        let expected = [
             1, 2, 8, 60, 320, 1980, 10512, 60788, 320896,
             1787904, 9381840, 51081844
         ];
@@ -265,7 +1037,7 @@ mod tests {
 
     #[test]
     fn test_sequence_n_3() {
-        let expected = vec![
+        let expected = [
             1, 6, 60, 1368, 15552, 201240, 2016432, 21582624
         ];
 
@@ -283,9 +1055,206 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_part_search_resumes_from_checkpoint() {
+        for part in 0..5 {
+            let expected = StampFolder::calculate_part_stats(&[3, 3], part, 5);
+
+            let mut search = PartSearch::new(&[3, 3], part, 5);
+            let mut previous = 0.0;
+            while !search.step(7) {
+                let fraction = search.fraction_done();
+                assert!((previous..=1.0).contains(&fraction), "fraction {} after {}", fraction, previous);
+                previous = fraction;
+                search = PartSearch::restore(&search.checkpoint()).unwrap();
+            }
+            assert_eq!(search.stats(), expected);
+            assert_eq!(search.fraction_done(), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_checkpoint_is_an_error() {
+        let mut search = PartSearch::new(&[3, 3], 1, 2);
+        search.step(40);
+        let text = search.checkpoint();
+        let lines: Vec<&str> = text.lines().collect();
+        // Every value replaced by something out of range either restores to a runnable search
+        // or is rejected, never a panic
+        for (i, line) in lines.iter().enumerate().skip(1) {
+            let (name, rest) = line.split_once(' ').unwrap();
+            for (j, _) in rest.split(' ').enumerate() {
+                for bad in ["-1", "0", "8", "10", "4000", "99999999999"] {
+                    let mut values: Vec<&str> = rest.split(' ').collect();
+                    values[j] = bad;
+                    let mut corrupt = lines.clone();
+                    let changed = format!("{} {}", name, values.join(" "));
+                    corrupt[i] = &changed;
+                    if let Ok(mut restored) = PartSearch::restore(&corrupt.join("\n")) {
+                        restored.step(10_000);
+                    }
+                }
+            }
+        }
+        assert!(PartSearch::restore(&text.replace("\nb ", "\nb 9 ")).is_err());
+        // Links that invert each other but split the stack into two rings
+        let split = text.replace("\na 4 0 3 1 6 2 5 ", "\na 1 0 4 2 6 3 5 ").replace("\nb 1 3 5 2 0 6 4 ", "\nb 1 0 3 5 2 6 4 ");
+        assert_ne!(split, text);
+        assert!(PartSearch::restore(&split).is_err());
+        assert!(PartSearch::restore(&text.replace("dims 3 3", "dims 3 3 3 3")).is_err());
+        assert!(PartSearch::restore(&text.replace("\ncount ", "\ncount -")).is_err());
+        assert!(PartSearch::restore(&text.replace("\nnodes ", "\nnodes -")).is_err());
+    }
+
+    #[test]
+    fn test_single_part_is_whole_count() {
+        for dimensions in [vec![6], vec![2, 4], vec![3, 3], vec![2, 2, 2]] {
+            assert_eq!(
+                StampFolder::calculate_sequence_part(&dimensions, 0, 1),
+                StampFolder::calculate_sequence(&dimensions),
+                "Failed for {:?}",
+                dimensions
+            );
+        }
+    }
+
+    #[test]
+    fn test_more_threads_than_leaves() {
+        assert_eq!(StampFolder::calculate_sequence_parallel(&[3, 3], 16), 1368);
+        assert_eq!(StampFolder::calculate_sequence_parallel(&[2, 2], 64), 8);
+    }
+
+    #[test]
+    fn test_subtrees_cover_the_search() {
+        for dimensions in [vec![7], vec![2, 4], vec![3, 3], vec![2, 2, 2]] {
+            let expected = StampFolder::calculate_sequence(&dimensions);
+            for depth in 0..5 {
+                let prefixes = StampFolder::subtree_prefixes(&dimensions, depth);
+                assert!(prefixes.iter().all(|prefix| prefix.len() == depth.min(dimensions.iter().product::<i32>() as usize - 1)));
+                let total: i64 = prefixes.iter().map(|prefix| StampFolder::count_subtree(&dimensions, prefix).count).sum();
+                assert_eq!(total, expected, "Failed for {:?} at depth {}", dimensions, depth);
+            }
+        }
+    }
+
+    #[test]
+    fn test_last_level_counting_matches_full_descent() {
+        let run = |dimensions: &[i32], count_last_level: bool, mode: GapMode, flag: bool, res: i32, mod_val: i32| {
+            let mut folder = StampFolder::new();
+            folder.count_last_level = count_last_level;
+            folder.set_gap_mode(mode);
+            folder.foldings(dimensions, flag, res, mod_val);
+            (folder.count, folder.nodes)
+        };
+
+        for dimensions in [vec![1], vec![2], vec![8], vec![2, 5], vec![3, 4], vec![2, 2, 2], vec![1, 3, 1, 2, 1]] {
+            let n: i32 = dimensions.iter().product();
+            for mode in [GapMode::List, GapMode::Bitmask] {
+                for flag in [true, false] {
+                    for (res, mod_val) in [(0, 0), (0, n.min(3)), (1, n.min(3))] {
+                        assert_eq!(
+                            run(&dimensions, true, mode, flag, res, mod_val),
+                            run(&dimensions, false, mode, flag, res, mod_val),
+                            "{:?} {:?} flag={} {}/{}",
+                            dimensions,
+                            mode,
+                            flag,
+                            res,
+                            mod_val
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_off_partitions_the_search() {
+        for dimensions in [vec![3, 4], vec![2, 2, 2], vec![9]] {
+            let mut whole = StampFolder::new();
+            whole.foldings(&dimensions, true, 0, 0);
+
+            let mut folder = StampFolder::new();
+            folder.start(&dimensions, true, 0, 0);
+            let mut count = 0;
+            let mut nodes = 0;
+            while !folder.resume(50) {
+                for prefix in folder.split_off() {
+                    let stats = StampFolder::count_subtree(&dimensions, &prefix);
+                    count += stats.count;
+                    nodes += stats.nodes;
+                }
+            }
+            assert_eq!((count + folder.count, nodes + folder.nodes), (whole.count, whole.nodes), "{:?}", dimensions);
+        }
+    }
+
+    #[test]
+    fn test_adaptive_matches_sequential() {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for dimensions in [vec![2, 2], vec![3, 3], vec![2, 6], vec![4, 4]] {
+            let mut folder = StampFolder::new();
+            folder.foldings(&dimensions, true, 0, 0);
+            let stats = pool.install(|| StampFolder::calculate_adaptive_stats(&dimensions));
+            assert_eq!(stats, PartStats { count: folder.count, nodes: folder.nodes }, "{:?}", dimensions);
+        }
+        assert_eq!(StampFolder::calculate_adaptive_stats(&[0, 3]).count, 1);
+    }
+
+    #[test]
+    fn test_unspecialized_dimensionality() {
+        // Extra dimensions of length 1 add no creases, and five of them take the generic path
+        for mode in [GapMode::List, GapMode::Bitmask] {
+            assert_eq!(StampFolder::calculate_sequence_with(&[2, 1, 3, 1, 1], mode), 60);
+            assert_eq!(
+                StampFolder::calculate_sequence_with(&[1, 2, 1, 2, 1, 2], mode),
+                StampFolder::calculate_sequence(&[2, 2, 2])
+            );
+        }
+    }
+
+    // Stacks completed by the DIM copy of the search, in the order it completes them
+    fn completed_stacks<const DIM: usize>(dimensions: &[i32], res: i32, mod_val: i32) -> Vec<Vec<i32>> {
+        let mut folder = StampFolder::new();
+        folder.count_last_level = false;
+        folder.start(dimensions, true, res, mod_val);
+        let mut stacks = Vec::new();
+        folder.resume_dim::<DIM, false, _>(u64::MAX, &mut |b: &[i32]| stacks.push(b[..MAX_N].to_vec()));
+        stacks
+    }
+
+    #[test]
+    fn test_two_axis_walks_keep_the_search_order() {
+        for dimensions in [[3, 3], [2, 5], [4, 3], [1, 6]] {
+            for (res, mod_val) in [(0, 0), (2, 5)] {
+                let stacks = completed_stacks::<2>(&dimensions, res, mod_val);
+                assert!(!stacks.is_empty());
+                assert_eq!(stacks, completed_stacks::<0>(&dimensions, res, mod_val), "{:?}", dimensions);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bitmask_gaps_match_lists() {
+        for dimensions in [vec![9], vec![2, 5], vec![3, 4], vec![2, 2, 3], vec![4, 4]] {
+            let expected = StampFolder::calculate_sequence(&dimensions);
+            assert_eq!(StampFolder::calculate_sequence_with(&dimensions, GapMode::Bitmask), expected);
+
+            let total: i64 = (0..3)
+                .map(|part| {
+                    let mut search = PartSearch::new(&dimensions, part, 3);
+                    search.set_gap_mode(GapMode::Bitmask);
+                    search.step(u64::MAX);
+                    search.stats().count
+                })
+                .sum();
+            assert_eq!(total, expected, "Failed for {:?} in parts", dimensions);
+        }
+    }
+
     #[test]
     fn test_sequence_n_n() {
-        let expected = vec![1, 1, 8, 1368, 300608];
+        let expected = [1, 1, 8, 1368, 300608];
 
         for (i, &expected_value) in expected.iter().enumerate() {
             let n = i as i32;
@@ -0,0 +1,322 @@
+//! Comparing two `.fold` files as sets of foldings: what only one of them has, what either lists
+//! more than once and what is not a folding at all.
+//!
+//! Both files are sorted externally: foldings are read in runs of at most `run_foldings`, each
+//! sorted and spilled to a temporary `.fold` file, and the runs merged back in order. Sorted runs
+//! share long prefixes, so they take little disk. Rotations are expanded, so a file of every
+//! folding and one of stacks with leaf 1 on top compare equal.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::fold_file::{FoldFileError, FoldReader, FoldWriter, Symmetry};
+use crate::folding::Folding;
+
+pub const DIFF_HEADER: &str = "folds-diff v1";
+
+#[derive(Clone, Debug)]
+pub struct DiffOptions {
+    /// Foldings held in memory per file before a sorted run is spilled to disk.
+    pub run_foldings: usize,
+    /// Foldings kept as examples of each kind of difference.
+    pub examples: usize,
+    pub temp_dir: PathBuf,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions { run_foldings: 1 << 22, examples: 10, temp_dir: std::env::temp_dir() }
+    }
+}
+
+/// What one side of a diff holds.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileSummary {
+    pub foldings: u64,
+    pub distinct: u64,
+    /// Extra copies of valid foldings.
+    pub duplicates: u64,
+    /// Records that are not foldings of the map; left out of everything else.
+    pub invalid: u64,
+    /// Distinct foldings the other file does not have.
+    pub only_here: u64,
+    pub duplicate_examples: Vec<Folding>,
+    pub invalid_examples: Vec<Folding>,
+    pub only_here_examples: Vec<Folding>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffReport {
+    pub dims: Vec<i32>,
+    pub files: [FileSummary; 2],
+}
+
+impl DiffReport {
+    /// Whether both files hold the same foldings, each once, and nothing else.
+    pub fn is_clean(&self) -> bool {
+        self.files.iter().all(|file| file.duplicates == 0 && file.invalid == 0 && file.only_here == 0)
+    }
+
+    pub fn to_text(&self, names: [&str; 2]) -> String {
+        let dims: Vec<String> = self.dims.iter().map(|d| d.to_string()).collect();
+        let mut text = format!("{}\ndims {}\n", DIFF_HEADER, dims.join(" "));
+        for (name, file) in names.iter().zip(&self.files) {
+            let _ = writeln!(
+                text,
+                "file {} foldings {} distinct {} duplicates {} invalid {} only {}",
+                name, file.foldings, file.distinct, file.duplicates, file.invalid, file.only_here
+            );
+        }
+        for (name, file) in names.iter().zip(&self.files) {
+            for (kind, examples) in [
+                ("only", &file.only_here_examples),
+                ("duplicate", &file.duplicate_examples),
+                ("invalid", &file.invalid_examples),
+            ] {
+                for folding in examples {
+                    let _ = writeln!(text, "{} {} {}", kind, name, folding);
+                }
+            }
+        }
+        text
+    }
+}
+
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// A spilled run, removed when dropped
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+enum Run {
+    Memory(std::vec::IntoIter<Folding>),
+    File { reader: FoldReader<BufReader<File>>, next_block: usize, block: std::vec::IntoIter<Folding>, _path: TempPath },
+}
+
+impl Run {
+    fn next(&mut self) -> Result<Option<Folding>, FoldFileError> {
+        match self {
+            Run::Memory(foldings) => Ok(foldings.next()),
+            Run::File { reader, next_block, block, .. } => loop {
+                if let Some(folding) = block.next() {
+                    return Ok(Some(folding));
+                }
+                if *next_block == reader.blocks() {
+                    return Ok(None);
+                }
+                *block = reader.read_block(*next_block)?.into_iter();
+                *next_block += 1;
+            },
+        }
+    }
+}
+
+// The runs of one file merged into one sorted stream
+struct Merged {
+    runs: Vec<Run>,
+    heap: BinaryHeap<Reverse<(Folding, usize)>>,
+}
+
+impl Merged {
+    fn new(mut runs: Vec<Run>) -> Result<Self, FoldFileError> {
+        let mut heap = BinaryHeap::new();
+        for (index, run) in runs.iter_mut().enumerate() {
+            if let Some(folding) = run.next()? {
+                heap.push(Reverse((folding, index)));
+            }
+        }
+        Ok(Merged { runs, heap })
+    }
+
+    fn peek(&self) -> Option<&Folding> {
+        self.heap.peek().map(|Reverse((folding, _))| folding)
+    }
+
+    fn next(&mut self) -> Result<Option<Folding>, FoldFileError> {
+        let Some(Reverse((folding, index))) = self.heap.pop() else {
+            return Ok(None);
+        };
+        if let Some(next) = self.runs[index].next()? {
+            self.heap.push(Reverse((next, index)));
+        }
+        Ok(Some(folding))
+    }
+
+    // The next folding, skipping and counting its further copies
+    fn next_distinct(&mut self, summary: &mut FileSummary, examples: usize) -> Result<Option<Folding>, FoldFileError> {
+        let Some(folding) = self.next()? else {
+            return Ok(None);
+        };
+        summary.distinct += 1;
+        while self.peek() == Some(&folding) {
+            self.next()?;
+            summary.duplicates += 1;
+            if summary.duplicate_examples.len() < examples && summary.duplicate_examples.last() != Some(&folding) {
+                summary.duplicate_examples.push(folding.clone());
+            }
+        }
+        Ok(Some(folding))
+    }
+}
+
+fn spill(run: &mut Vec<Folding>, dims: &[i32], options: &DiffOptions) -> Result<Run, FoldFileError> {
+    run.sort_unstable();
+    let name = format!("folds-diff-{}-{}.fold", std::process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed));
+    let path = TempPath(options.temp_dir.join(name));
+    let mut writer = FoldWriter::new(BufWriter::new(File::create(&path.0)?), dims, Symmetry::All)?;
+    for folding in run.drain(..) {
+        writer.push(&folding.order)?;
+    }
+    writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    let reader = FoldReader::open(BufReader::new(File::open(&path.0)?))?;
+    Ok(Run::File { reader, next_block: 0, block: Vec::new().into_iter(), _path: path })
+}
+
+// Reads every folding of a file into sorted runs, setting invalid ones aside
+fn sorted<R: Read + Seek>(
+    reader: &mut FoldReader<R>,
+    summary: &mut FileSummary,
+    options: &DiffOptions,
+) -> Result<Merged, FoldFileError> {
+    reader.verify()?;
+    let dims = reader.dims().to_vec();
+    let mut runs = Vec::new();
+    let mut run = Vec::new();
+    let mut result = Ok(());
+    reader.for_each_folding(|folding| {
+        if result.is_err() {
+            return;
+        }
+        summary.foldings += 1;
+        if !folding.is_valid(&dims) {
+            summary.invalid += 1;
+            if summary.invalid_examples.len() < options.examples {
+                summary.invalid_examples.push(folding.clone());
+            }
+            return;
+        }
+        run.push(folding.clone());
+        if run.len() >= options.run_foldings.max(1) {
+            result = spill(&mut run, &dims, options).map(|spilled| runs.push(spilled));
+        }
+    })?;
+    result?;
+    run.sort_unstable();
+    runs.push(Run::Memory(run.into_iter()));
+    Merged::new(runs)
+}
+
+/// Compares two files of foldings of the same map. Both checksums are verified first.
+pub fn diff<R: Read + Seek, S: Read + Seek>(
+    a: &mut FoldReader<R>,
+    b: &mut FoldReader<S>,
+    options: &DiffOptions,
+) -> Result<DiffReport, FoldFileError> {
+    if a.dims() != b.dims() {
+        return Err(FoldFileError::DimsMismatch { first: a.dims().to_vec(), second: b.dims().to_vec() });
+    }
+    let _span = tracing::debug_span!("diff", dims = ?a.dims()).entered();
+    let mut files = [FileSummary::default(), FileSummary::default()];
+    let [first, second] = &mut files;
+    let mut left = sorted(a, first, options)?;
+    let mut right = sorted(b, second, options)?;
+
+    let mut x = left.next_distinct(first, options.examples)?;
+    let mut y = right.next_distinct(second, options.examples)?;
+    loop {
+        let take_left = match (&x, &y) {
+            (None, None) => break,
+            (Some(f), Some(g)) if f == g => {
+                x = left.next_distinct(first, options.examples)?;
+                y = right.next_distinct(second, options.examples)?;
+                continue;
+            }
+            (Some(f), Some(g)) => f < g,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+        };
+        let (summary, stream, current) =
+            if take_left { (&mut *first, &mut left, &mut x) } else { (&mut *second, &mut right, &mut y) };
+        summary.only_here += 1;
+        let folding = current.take().unwrap();
+        if summary.only_here_examples.len() < options.examples {
+            summary.only_here_examples.push(folding);
+        }
+        *current = stream.next_distinct(summary, options.examples)?;
+    }
+    Ok(DiffReport { dims: a.dims().to_vec(), files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enumerate::{for_each_folding, write_foldings};
+    use std::io::Cursor;
+
+    fn file(dims: &[i32], stacks: &[Folding]) -> FoldReader<Cursor<Vec<u8>>> {
+        let mut writer = FoldWriter::new(Cursor::new(Vec::new()), dims, Symmetry::All).unwrap();
+        for stack in stacks {
+            writer.push(&stack.order).unwrap();
+        }
+        FoldReader::open(Cursor::new(writer.finish().unwrap().into_inner())).unwrap()
+    }
+
+    #[test]
+    fn test_same_foldings_in_either_symmetry() {
+        let dims = [2, 4];
+        let (all, _) = write_foldings(&dims, Symmetry::All, Cursor::new(Vec::new())).unwrap();
+        let (rotations, _) = write_foldings(&dims, Symmetry::Rotations, Cursor::new(Vec::new())).unwrap();
+        let mut a = FoldReader::open(Cursor::new(all.into_inner())).unwrap();
+        let mut b = FoldReader::open(Cursor::new(rotations.into_inner())).unwrap();
+        let report = diff(&mut a, &mut b, &DiffOptions::default()).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.files[0], report.files[1]);
+        assert_eq!(report.files[0].distinct as i64, crate::cpu::StampFolder::calculate_sequence(&dims));
+
+        let mut other = file(&[4, 2], &[]);
+        assert!(matches!(diff(&mut a, &mut other, &DiffOptions::default()), Err(FoldFileError::DimsMismatch { .. })));
+    }
+
+    #[test]
+    fn test_differences_through_spilled_runs() {
+        let dims = [2, 3];
+        let mut all = Vec::new();
+        for_each_folding(&dims, |folding| all.push(folding.clone()));
+        let not_a_folding = Folding::new(vec![1, 3, 2, 4, 5, 6]);
+        assert!(!not_a_folding.is_valid(&dims));
+
+        // The second file misses two foldings, lists one twice more and holds a non-folding
+        let mut changed: Vec<Folding> = all[2..].iter().rev().cloned().collect();
+        changed.extend([all[10].clone(), all[10].clone(), not_a_folding.clone()]);
+
+        let temp_dir = std::env::temp_dir().join(format!("folds-diff-test-{}", std::process::id()));
+        fs::create_dir_all(&temp_dir).unwrap();
+        let options = DiffOptions { run_foldings: 7, examples: 1, temp_dir: temp_dir.clone() };
+        let report = diff(&mut file(&dims, &all), &mut file(&dims, &changed), &options).unwrap();
+        assert!(fs::read_dir(&temp_dir).unwrap().next().is_none(), "spilled runs are removed");
+        fs::remove_dir(&temp_dir).unwrap();
+
+        let [first, second] = &report.files;
+        assert!(!report.is_clean());
+        assert_eq!((first.foldings, first.distinct, first.only_here), (60, 60, 2));
+        assert_eq!(first.only_here_examples, vec![all[..2].iter().min().unwrap().clone()]);
+        assert_eq!((second.foldings, second.distinct, second.duplicates, second.invalid), (61, 58, 2, 1));
+        assert_eq!(second.duplicate_examples, vec![all[10].clone()]);
+        assert_eq!(second.invalid_examples, vec![not_a_folding]);
+        assert_eq!(second.only_here, 0);
+
+        let text = report.to_text(["a.fold", "b.fold"]);
+        assert!(text.starts_with("folds-diff v1\ndims 2 3\nfile a.fold foldings 60 distinct 60 duplicates 0 invalid 0 only 2\n"));
+        assert!(text.contains("\ninvalid b.fold 1 3 2 4 5 6\n"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Every folding of a map, one at a time, in rank order (see `rank`): the stacks the search
+//! completes, each followed by its rotations.
+
+use std::io::{Seek, Write};
+
+use crate::cpu::StampFolder;
+use crate::fold_file::{FoldFileError, FoldWriter, Symmetry};
+use crate::folding::Folding;
+
+/// Calls `visit` with each folding of `dims`. The folding passed in is reused between calls.
+pub fn for_each_folding(dims: &[i32], mut visit: impl FnMut(&Folding)) {
+    if dims.contains(&0) {
+        visit(&Folding::new(Vec::new()));
+        return;
+    }
+
+    let _span = tracing::debug_span!("enumerate", ?dims).entered();
+    let mut folder = StampFolder::new();
+    folder.start(dims, true, 0, 0);
+    let mut folding = Folding::new(Vec::new());
+    folder.visit_stacks(|stack| {
+        for shift in 0..stack.len() {
+            folding.order.clear();
+            folding.order.extend_from_slice(&stack[shift..]);
+            folding.order.extend_from_slice(&stack[..shift]);
+            visit(&folding);
+        }
+    });
+}
+
+/// Streams the foldings of `dims` into a `.fold` file, in the same order: every folding for
+/// `Symmetry::All`, just the stacks the search completes for `Symmetry::Rotations`. Returns the
+/// number of records written.
+pub fn write_foldings<W: Write + Seek>(dims: &[i32], symmetry: Symmetry, out: W) -> Result<(W, u64), FoldFileError> {
+    let mut writer = FoldWriter::new(out, dims, symmetry)?;
+    let mut records = 0;
+    let mut result = Ok(());
+    let mut push = |stack: &[i32]| {
+        if result.is_ok() {
+            result = writer.push(stack);
+            records += 1;
+        }
+    };
+    match symmetry {
+        Symmetry::All => for_each_folding(dims, |folding| push(&folding.order)),
+        Symmetry::Rotations if dims.contains(&0) => push(&[]),
+        Symmetry::Rotations => {
+            let _span = tracing::debug_span!("enumerate", ?dims).entered();
+            let mut folder = StampFolder::new();
+            folder.start(dims, true, 0, 0);
+            folder.visit_stacks(push);
+        }
+    }
+    result?;
+    Ok((writer.finish()?, records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rank::Ranker;
+
+    #[test]
+    fn test_enumeration_is_in_rank_order() {
+        for dims in [vec![6], vec![2, 3], vec![3, 3], vec![2, 2, 2]] {
+            let mut ranker = Ranker::new(&dims);
+            let mut index = 0;
+            for_each_folding(&dims, |folding| {
+                assert!(folding.is_valid(&dims));
+                if index % 97 == 0 {
+                    assert_eq!(ranker.unrank(index).as_ref(), Some(folding), "{:?} {}", dims, index);
+                }
+                index += 1;
+            });
+            assert_eq!(index, StampFolder::calculate_sequence(&dims) as u128);
+        }
+    }
+
+    #[test]
+    fn test_written_file_reads_back_in_order() {
+        use crate::fold_file::FoldReader;
+        use std::io::Cursor;
+
+        let dims = [2, 4];
+        let mut expected = Vec::new();
+        for_each_folding(&dims, |folding| expected.push(folding.clone()));
+        for symmetry in [Symmetry::All, Symmetry::Rotations] {
+            let (out, records) = write_foldings(&dims, symmetry, Cursor::new(Vec::new())).unwrap();
+            let mut reader = FoldReader::open(Cursor::new(out.into_inner())).unwrap();
+            reader.verify().unwrap();
+            assert_eq!(reader.records(), records);
+            let mut read = Vec::new();
+            reader.for_each_folding(|folding| read.push(folding.clone())).unwrap();
+            assert_eq!(read, expected);
+        }
+    }
+}
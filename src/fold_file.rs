@@ -0,0 +1,469 @@
+//! The `.fold` binary format: a stream of foldings of one map, written as they are enumerated
+//! and read back block by block.
+//!
+//! ```text
+//! header   "FOLD" version:u8 symmetry:u8 axes:u8 dims:u32... block_records:u32 records:u64
+//! blocks   records, each the number of leaves shared with the previous record of the block
+//!          and then the rest of its leaves, all as LEB128 varints
+//! index    the file offset of each block, u64
+//! trailer  index_offset:u64 blocks:u64 digest:[u8; 32]
+//! ```
+//!
+//! Integers are little-endian. A block holds `block_records` records (the last one fewer) and
+//! starts from an empty previous record, so it decodes on its own. The records count sits in the
+//! header but is only known at the end, which is why the writer needs `Seek`; the digest is
+//! SHA-256 over the SHA-256 of the header followed by the SHA-256 of the rest up to the digest.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use sha2::{Digest, Sha256};
+
+use crate::folding::{leaf_count, Folding};
+
+pub const MAGIC: &[u8; 4] = b"FOLD";
+pub const VERSION: u8 = 1;
+pub const DEFAULT_BLOCK_RECORDS: u32 = 4096;
+
+const TRAILER_LEN: u64 = 8 + 8 + 32;
+
+/// Which foldings the records stand for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Symmetry {
+    /// Every folding is a record.
+    All,
+    /// Records are the stacks with leaf 1 on top; each stands for its n rotations.
+    Rotations,
+}
+
+impl Symmetry {
+    fn code(self) -> u8 {
+        match self {
+            Symmetry::All => 0,
+            Symmetry::Rotations => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Symmetry::All),
+            1 => Some(Symmetry::Rotations),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FoldFileError {
+    Io(io::Error),
+    /// The bytes are not a `.fold` file this version can read.
+    Format(String),
+    /// A record does not fit the file's map or symmetry.
+    InvalidRecord(String),
+    ChecksumMismatch,
+    /// Two files that should be about the same map are not.
+    DimsMismatch { first: Vec<i32>, second: Vec<i32> },
+}
+
+impl fmt::Display for FoldFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FoldFileError::Io(e) => write!(f, "{}", e),
+            FoldFileError::Format(message) => write!(f, "not a valid .fold file: {}", message),
+            FoldFileError::InvalidRecord(message) => write!(f, "invalid record: {}", message),
+            FoldFileError::ChecksumMismatch => write!(f, "checksum does not match the contents"),
+            FoldFileError::DimsMismatch { first, second } => {
+                write!(f, "files are for different maps: {:?} and {:?}", first, second)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FoldFileError {}
+
+impl From<io::Error> for FoldFileError {
+    fn from(e: io::Error) -> Self {
+        FoldFileError::Io(e)
+    }
+}
+
+fn format_error(message: impl Into<String>) -> FoldFileError {
+    FoldFileError::Format(message.into())
+}
+
+fn encode_header(dims: &[i32], symmetry: Symmetry, block_records: u32, records: u64) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend([VERSION, symmetry.code(), dims.len() as u8]);
+    for &d in dims {
+        header.extend((d as u32).to_le_bytes());
+    }
+    header.extend(block_records.to_le_bytes());
+    header.extend(records.to_le_bytes());
+    header
+}
+
+fn push_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], at: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*at)?;
+        *at += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Writes records one at a time; nothing is readable until `finish`.
+pub struct FoldWriter<W: Write + Seek> {
+    out: W,
+    dims: Vec<i32>,
+    symmetry: Symmetry,
+    block_records: u32,
+    header_len: u64,
+    hasher: Sha256,
+    position: u64,
+    records: u64,
+    offsets: Vec<u64>,
+    previous: Vec<i32>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write + Seek> FoldWriter<W> {
+    pub fn new(out: W, dims: &[i32], symmetry: Symmetry) -> Result<Self, FoldFileError> {
+        FoldWriter::with_block_records(out, dims, symmetry, DEFAULT_BLOCK_RECORDS)
+    }
+
+    pub fn with_block_records(
+        mut out: W,
+        dims: &[i32],
+        symmetry: Symmetry,
+        block_records: u32,
+    ) -> Result<Self, FoldFileError> {
+        if dims.len() > u8::MAX as usize || dims.iter().any(|&d| d < 0) || block_records == 0 {
+            return Err(format_error(format!("cannot write {:?} in blocks of {}", dims, block_records)));
+        }
+        let header = encode_header(dims, symmetry, block_records, 0);
+        out.write_all(&header)?;
+        Ok(FoldWriter {
+            out,
+            dims: dims.to_vec(),
+            symmetry,
+            block_records,
+            header_len: header.len() as u64,
+            hasher: Sha256::new(),
+            position: header.len() as u64,
+            records: 0,
+            offsets: Vec::new(),
+            previous: Vec::new(),
+            buffer: Vec::new(),
+        })
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hasher.update(bytes);
+        self.position += bytes.len() as u64;
+        self.out.write_all(bytes)
+    }
+
+    /// Appends one stack, top first. Leaves are not checked to form a folding.
+    pub fn push(&mut self, stack: &[i32]) -> Result<(), FoldFileError> {
+        let n = leaf_count(&self.dims);
+        if stack.len() != n || stack.iter().any(|&leaf| leaf < 1 || leaf as usize > n) {
+            return Err(FoldFileError::InvalidRecord(format!("{:?} is not a stack of {} leaves", stack, n)));
+        }
+        if self.symmetry == Symmetry::Rotations && n > 0 && stack[0] != 1 {
+            return Err(FoldFileError::InvalidRecord(format!("{:?} does not have leaf 1 on top", stack)));
+        }
+
+        if self.records.is_multiple_of(self.block_records as u64) {
+            self.offsets.push(self.position);
+            self.previous.clear();
+        }
+        let shared = self.previous.iter().zip(stack).take_while(|(a, b)| a == b).count();
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+        push_varint(&mut buffer, shared as u64);
+        for &leaf in &stack[shared..] {
+            push_varint(&mut buffer, leaf as u64);
+        }
+        self.emit(&buffer)?;
+        self.buffer = buffer;
+        self.previous.clear();
+        self.previous.extend_from_slice(stack);
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Writes the index and trailer, fills in the header's record count and hands back the output.
+    pub fn finish(mut self) -> Result<W, FoldFileError> {
+        let index_offset = self.position;
+        let mut tail = Vec::with_capacity(8 * (self.offsets.len() + 2));
+        for offset in &self.offsets {
+            tail.extend(offset.to_le_bytes());
+        }
+        tail.extend(index_offset.to_le_bytes());
+        tail.extend((self.offsets.len() as u64).to_le_bytes());
+        self.emit(&tail)?;
+
+        let header = encode_header(&self.dims, self.symmetry, self.block_records, self.records);
+        let digest = combined_digest(&header, self.hasher.finalize().into());
+        self.out.write_all(&digest)?;
+        self.out.seek(SeekFrom::Start(self.header_len - 8))?;
+        self.out.write_all(&self.records.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn combined_digest(header: &[u8], body: [u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(Sha256::digest(header));
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+/// Reads a `.fold` file: its header on opening, then any block on demand.
+pub struct FoldReader<R: Read + Seek> {
+    input: R,
+    dims: Vec<i32>,
+    symmetry: Symmetry,
+    block_records: u32,
+    records: u64,
+    header_len: u64,
+    // Block offsets followed by the index offset, where the last block ends
+    offsets: Vec<u64>,
+    digest: [u8; 32],
+}
+
+impl<R: Read + Seek> FoldReader<R> {
+    pub fn open(mut input: R) -> Result<Self, FoldFileError> {
+        let mut fixed = [0u8; 7];
+        input.read_exact(&mut fixed).map_err(|_| format_error("file too short"))?;
+        if &fixed[..4] != MAGIC {
+            return Err(format_error("missing FOLD magic"));
+        }
+        if fixed[4] != VERSION {
+            return Err(format_error(format!("version {} (this is version {})", fixed[4], VERSION)));
+        }
+        let symmetry = Symmetry::from_code(fixed[5]).ok_or_else(|| format_error("unknown symmetry"))?;
+        let mut rest = vec![0u8; 4 * fixed[6] as usize + 12];
+        input.read_exact(&mut rest).map_err(|_| format_error("truncated header"))?;
+        let (dims, counts) = rest.split_at(4 * fixed[6] as usize);
+        let dims: Vec<i32> = dims.chunks(4).map(|d| u32::from_le_bytes(d.try_into().unwrap()) as i32).collect();
+        let block_records = u32::from_le_bytes(counts[..4].try_into().unwrap());
+        let records = u64::from_le_bytes(counts[4..].try_into().unwrap());
+        if dims.iter().any(|&d| d < 0) || block_records == 0 {
+            return Err(format_error("invalid header"));
+        }
+        let header_len = 7 + rest.len() as u64;
+
+        let len = input.seek(SeekFrom::End(0))?;
+        if len < header_len + TRAILER_LEN {
+            return Err(format_error("file too short"));
+        }
+        input.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        input.read_exact(&mut trailer)?;
+        let index_offset = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+        let blocks = u64::from_le_bytes(trailer[8..16].try_into().unwrap());
+        let digest: [u8; 32] = trailer[16..].try_into().unwrap();
+        if blocks != records.div_ceil(block_records as u64)
+            || index_offset < header_len
+            || index_offset.checked_add(8 * blocks) != Some(len - TRAILER_LEN)
+        {
+            return Err(format_error("index does not match the header"));
+        }
+
+        input.seek(SeekFrom::Start(index_offset))?;
+        let mut index = vec![0u8; 8 * blocks as usize];
+        input.read_exact(&mut index)?;
+        let mut offsets: Vec<u64> = index.chunks(8).map(|o| u64::from_le_bytes(o.try_into().unwrap())).collect();
+        offsets.push(index_offset);
+        if offsets.first().is_some_and(|&first| first != header_len) || offsets.windows(2).any(|w| w[0] > w[1]) {
+            return Err(format_error("block offsets out of order"));
+        }
+        Ok(FoldReader { input, dims, symmetry, block_records, records, header_len, offsets, digest })
+    }
+
+    pub fn dims(&self) -> &[i32] {
+        &self.dims
+    }
+
+    pub fn symmetry(&self) -> Symmetry {
+        self.symmetry
+    }
+
+    /// Number of records in the file.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Number of foldings the records stand for.
+    pub fn folding_count(&self) -> u128 {
+        match self.symmetry {
+            Symmetry::All => self.records as u128,
+            Symmetry::Rotations => self.records as u128 * leaf_count(&self.dims) as u128,
+        }
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// The records of block `block`, in file order.
+    pub fn read_block(&mut self, block: usize) -> Result<Vec<Folding>, FoldFileError> {
+        if block >= self.blocks() {
+            return Err(format_error(format!("no block {} of {}", block, self.blocks())));
+        }
+        let (start, end) = (self.offsets[block], self.offsets[block + 1]);
+        let mut bytes = vec![0u8; (end - start) as usize];
+        self.input.seek(SeekFrom::Start(start))?;
+        self.input.read_exact(&mut bytes)?;
+
+        let expected = (self.records - block as u64 * self.block_records as u64).min(self.block_records as u64);
+        let n = leaf_count(&self.dims);
+        let mut foldings: Vec<Folding> = Vec::with_capacity(expected as usize);
+        let mut at = 0;
+        for _ in 0..expected {
+            let truncated = || format_error(format!("block {} is truncated", block));
+            let shared = read_varint(&bytes, &mut at).ok_or_else(truncated)? as usize;
+            let mut order = match foldings.last() {
+                Some(previous) if shared <= n => previous.order[..shared].to_vec(),
+                None if shared == 0 => Vec::with_capacity(n),
+                _ => return Err(format_error(format!("block {}: bad shared prefix {}", block, shared))),
+            };
+            while order.len() < n {
+                let leaf = read_varint(&bytes, &mut at).ok_or_else(truncated)?;
+                if leaf == 0 || leaf > n as u64 {
+                    return Err(format_error(format!("block {}: leaf {} out of range", block, leaf)));
+                }
+                order.push(leaf as i32);
+            }
+            foldings.push(Folding::new(order));
+        }
+        if at != bytes.len() {
+            return Err(format_error(format!("block {} has trailing bytes", block)));
+        }
+        Ok(foldings)
+    }
+
+    /// Record `index`, reading only its block.
+    pub fn get(&mut self, index: u64) -> Result<Folding, FoldFileError> {
+        if index >= self.records {
+            return Err(format_error(format!("no record {} of {}", index, self.records)));
+        }
+        let block = self.read_block((index / self.block_records as u64) as usize)?;
+        Ok(block[(index % self.block_records as u64) as usize].clone())
+    }
+
+    /// Calls `visit` with every folding the file stands for, expanding rotations, in file order.
+    pub fn for_each_folding(&mut self, mut visit: impl FnMut(&Folding)) -> Result<(), FoldFileError> {
+        for block in 0..self.blocks() {
+            for record in self.read_block(block)? {
+                match self.symmetry {
+                    Symmetry::All => visit(&record),
+                    Symmetry::Rotations => (0..record.order.len().max(1)).for_each(|r| visit(&record.rotated(r))),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Recomputes the digest over the whole file.
+    pub fn verify(&mut self) -> Result<(), FoldFileError> {
+        self.input.seek(SeekFrom::Start(0))?;
+        let mut header = vec![0u8; self.header_len as usize];
+        self.input.read_exact(&mut header)?;
+
+        let body_len = self.offsets[self.blocks()] + 8 * self.blocks() as u64 + 16 - self.header_len;
+        let mut hasher = Sha256::new();
+        let mut chunk = vec![0u8; 1 << 16];
+        let mut left = body_len;
+        while left > 0 {
+            let take = left.min(chunk.len() as u64) as usize;
+            self.input.read_exact(&mut chunk[..take])?;
+            hasher.update(&chunk[..take]);
+            left -= take as u64;
+        }
+        if combined_digest(&header, hasher.finalize().into()) != self.digest {
+            return Err(FoldFileError::ChecksumMismatch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enumerate::for_each_folding;
+    use std::io::Cursor;
+
+    fn write(dims: &[i32], symmetry: Symmetry, block_records: u32, stacks: &[Folding]) -> Vec<u8> {
+        let mut writer = FoldWriter::with_block_records(Cursor::new(Vec::new()), dims, symmetry, block_records).unwrap();
+        for stack in stacks {
+            writer.push(&stack.order).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_round_trip_and_random_access() {
+        let dims = [3, 3];
+        let mut all = Vec::new();
+        for_each_folding(&dims, |folding| all.push(folding.clone()));
+        let normalized: Vec<Folding> = all.iter().filter(|folding| folding.order[0] == 1).cloned().collect();
+
+        for (symmetry, stacks) in [(Symmetry::All, &all), (Symmetry::Rotations, &normalized)] {
+            let bytes = write(&dims, symmetry, 100, stacks);
+            let mut reader = FoldReader::open(Cursor::new(bytes)).unwrap();
+            reader.verify().unwrap();
+            assert_eq!((reader.dims(), reader.symmetry()), (&dims[..], symmetry));
+            assert_eq!(reader.records(), stacks.len() as u64);
+            assert_eq!(reader.folding_count(), all.len() as u128);
+            assert_eq!(reader.blocks(), stacks.len().div_ceil(100));
+            for index in [0, 99, 100, stacks.len() as u64 - 1] {
+                assert_eq!(&reader.get(index).unwrap(), &stacks[index as usize]);
+            }
+            let mut read = Vec::new();
+            reader.for_each_folding(|folding| read.push(folding.clone())).unwrap();
+            read.sort();
+            let mut expected = all.clone();
+            expected.sort();
+            assert_eq!(read, expected);
+        }
+
+        // Stacks in search order share prefixes: less than a byte per leaf
+        let bytes = write(&dims, Symmetry::Rotations, DEFAULT_BLOCK_RECORDS, &normalized);
+        assert!(bytes.len() < 9 * normalized.len(), "{} bytes", bytes.len());
+    }
+
+    #[test]
+    fn test_rejects_damage() {
+        let stacks = [Folding::new(vec![1, 2, 4, 3]), Folding::new(vec![1, 3, 4, 2])];
+        let bytes = write(&[2, 2], Symmetry::Rotations, 1, &stacks);
+        assert!(FoldReader::open(Cursor::new(bytes.clone())).unwrap().verify().is_ok());
+
+        let mut flipped = bytes.clone();
+        flipped[30] ^= 1;
+        let mut reader = FoldReader::open(Cursor::new(flipped)).unwrap();
+        assert!(matches!(reader.verify(), Err(FoldFileError::ChecksumMismatch)));
+
+        assert!(matches!(FoldReader::open(Cursor::new(&bytes[..40])), Err(FoldFileError::Format(_))));
+        assert!(matches!(FoldReader::open(Cursor::new(b"FOLX".to_vec())), Err(FoldFileError::Format(_))));
+
+        let mut writer = FoldWriter::new(Cursor::new(Vec::new()), &[2, 2], Symmetry::Rotations).unwrap();
+        assert!(matches!(writer.push(&[2, 1, 3, 4]), Err(FoldFileError::InvalidRecord(_))));
+        assert!(matches!(writer.push(&[1, 2, 5, 3]), Err(FoldFileError::InvalidRecord(_))));
+    }
+}
@@ -0,0 +1,300 @@
+//! Single foldings: the order of the leaves in the folded stack, the check that an order folds
+//! flat, and the search tree the counts come from, walked one branch at a time.
+//!
+//! Leaves are numbered as in the search: leaf `1 + x1 + p1 * x2 + p1 * p2 * x3 + ...` is the
+//! cell at zero-based coordinates `(x1, x2, ...)`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cpu::StampFolder;
+
+/// Leaves from the top of the stack to the bottom.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Folding {
+    pub order: Vec<i32>,
+}
+
+impl Folding {
+    pub fn new(order: Vec<i32>) -> Self {
+        Folding { order }
+    }
+
+    /// The stack the search builds from `gaps`, the gap chosen for each of leaves 1, 2, ...:
+    /// leaf k goes directly below leaf `gaps[k - 1]`, gap 0 being the top.
+    pub fn from_gaps(gaps: &[i32]) -> Self {
+        let mut below = vec![0; gaps.len() + 1];
+        for (k, &m) in gaps.iter().enumerate() {
+            below[k + 1] = below[m as usize];
+            below[m as usize] = k as i32 + 1;
+        }
+
+        let mut order = Vec::with_capacity(gaps.len());
+        let mut leaf = below[0];
+        while leaf != 0 {
+            order.push(leaf);
+            leaf = below[leaf as usize];
+        }
+        Folding { order }
+    }
+
+    /// The same stack with its top `shift` leaves moved to the bottom. A flat folding stays flat,
+    /// so every folding is a rotation of exactly one with leaf 1 on top.
+    pub fn rotated(&self, shift: usize) -> Folding {
+        let mut order = self.order.clone();
+        if !order.is_empty() {
+            let shift = shift % order.len();
+            order.rotate_left(shift);
+        }
+        Folding { order }
+    }
+
+    /// `position[leaf]` is the leaf's place in the stack, counted from the top (index 0 unused).
+    pub fn positions(&self) -> Vec<usize> {
+        let mut position = vec![0; self.order.len() + 1];
+        for (index, &leaf) in self.order.iter().enumerate() {
+            position[leaf as usize] = index;
+        }
+        position
+    }
+
+    /// Whether this is a flat folding of the map: a permutation of the leaves in which no two
+    /// creases on the same edge of the stack cross. Along axis i the crease after coordinate x
+    /// lies on one edge for even x and on the opposite edge for odd x.
+    pub fn is_valid(&self, dims: &[i32]) -> bool {
+        let n = leaf_count(dims);
+        if self.order.len() != n {
+            return false;
+        }
+        let mut seen = vec![false; n + 1];
+        for &leaf in &self.order {
+            if leaf < 1 || leaf as usize > n || seen[leaf as usize] {
+                return false;
+            }
+            seen[leaf as usize] = true;
+        }
+
+        let position = self.positions();
+        let mut stride = 1;
+        for &p in dims {
+            for parity in 0..2 {
+                let mut creases: Vec<(usize, usize)> = (1..=n)
+                    .filter(|&leaf| {
+                        let x = (leaf - 1) / stride % p as usize;
+                        x + 1 < p as usize && x % 2 == parity
+                    })
+                    .map(|leaf| {
+                        let (u, v) = (position[leaf], position[leaf + stride]);
+                        (u.min(v), u.max(v))
+                    })
+                    .collect();
+                if !nested_or_disjoint(&mut creases) {
+                    return false;
+                }
+            }
+            stride *= p as usize;
+        }
+        true
+    }
+}
+
+/// How a crease is folded, seen from the side the front of leaf 1 faces: a valley brings the
+/// front faces of its two leaves together, a mountain their backs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Fold {
+    Mountain,
+    Valley,
+}
+
+/// The crease between `leaf` and the next leaf along `axis`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crease {
+    pub axis: usize,
+    pub leaf: i32,
+    pub fold: Fold,
+}
+
+impl Folding {
+    /// Every crease of the map with its fold, axis by axis and by leaf within an axis. Leaves an
+    /// even number of steps from leaf 1 face the way it does, the others are turned over.
+    pub fn creases(&self, dims: &[i32]) -> Vec<Crease> {
+        let position = self.positions();
+        let n = self.order.len() as i32;
+        let mut creases = Vec::new();
+        let mut stride = 1;
+        for (axis, &p) in dims.iter().enumerate() {
+            for leaf in 1..=n {
+                if (leaf - 1) / stride % p + 1 == p {
+                    continue;
+                }
+                let front_up = coordinates(dims, leaf).iter().sum::<i32>() % 2 == 0;
+                let next_above = position[(leaf + stride) as usize] < position[leaf as usize];
+                let fold = if front_up == next_above { Fold::Valley } else { Fold::Mountain };
+                creases.push(Crease { axis, leaf, fold });
+            }
+            stride *= p;
+        }
+        creases
+    }
+}
+
+// Creases on one edge, as (upper, lower) stack positions, must pairwise nest or be disjoint
+pub(crate) fn nested_or_disjoint(creases: &mut [(usize, usize)]) -> bool {
+    creases.sort_unstable();
+    let mut open: Vec<usize> = Vec::new();
+    for &(upper, lower) in creases.iter() {
+        while open.last().is_some_and(|&end| end < upper) {
+            open.pop();
+        }
+        if open.last().is_some_and(|&end| end < lower) {
+            return false;
+        }
+        open.push(lower);
+    }
+    true
+}
+
+pub(crate) fn leaf_count(dims: &[i32]) -> usize {
+    dims.iter().product::<i32>().max(0) as usize
+}
+
+/// Zero-based coordinates of a leaf.
+pub fn coordinates(dims: &[i32], leaf: i32) -> Vec<i32> {
+    let mut rest = leaf - 1;
+    dims.iter()
+        .map(|&p| {
+            let x = rest % p;
+            rest /= p;
+            x
+        })
+        .collect()
+}
+
+impl fmt::Display for Folding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let leaves: Vec<String> = self.order.iter().map(|leaf| leaf.to_string()).collect();
+        write!(f, "{}", leaves.join(" "))
+    }
+}
+
+impl FromStr for Folding {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        text.split_whitespace()
+            .map(|leaf| leaf.parse().map_err(|_| format!("invalid leaf `{}`", leaf)))
+            .collect::<Result<_, _>>()
+            .map(Folding::new)
+    }
+}
+
+/// The normalized search tree (leaf 1 on top), walked by gap prefixes, with the number of
+/// foldings below each prefix counted once and kept.
+pub(crate) struct SubtreeCounts {
+    dims: Vec<i32>,
+    folder: StampFolder,
+    counts: HashMap<Vec<i32>, i64>,
+}
+
+impl SubtreeCounts {
+    pub(crate) fn new(dims: &[i32]) -> Self {
+        SubtreeCounts { dims: dims.to_vec(), folder: StampFolder::new(), counts: HashMap::new() }
+    }
+
+    pub(crate) fn leaves(&self) -> usize {
+        leaf_count(&self.dims)
+    }
+
+    /// The gaps the search tries for the leaf after `prefix`, in its order.
+    pub(crate) fn children(&mut self, prefix: &[i32]) -> Vec<i32> {
+        self.folder.start_subtree(&self.dims, prefix);
+        self.folder.next_gaps()
+    }
+
+    /// Foldings below `prefix`, rotations included (n per stack with leaf 1 on top).
+    pub(crate) fn count(&mut self, prefix: &[i32]) -> i64 {
+        if let Some(&count) = self.counts.get(prefix) {
+            return count;
+        }
+        let before = self.folder.count;
+        self.folder.start_subtree(&self.dims, prefix);
+        self.folder.resume(u64::MAX);
+        let count = self.folder.count - before;
+        self.counts.insert(prefix.to_vec(), count);
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every permutation of 1..=n, by Heap's algorithm
+    fn permutations(n: usize) -> Vec<Vec<i32>> {
+        let mut order: Vec<i32> = (1..=n as i32).collect();
+        let mut counters = vec![0; n];
+        let mut all = vec![order.clone()];
+        let mut i = 0;
+        while i < n {
+            if counters[i] < i {
+                order.swap(if i % 2 == 0 { 0 } else { counters[i] }, i);
+                all.push(order.clone());
+                counters[i] += 1;
+                i = 0;
+            } else {
+                counters[i] = 0;
+                i += 1;
+            }
+        }
+        all
+    }
+
+    #[test]
+    fn test_validity_agrees_with_the_count() {
+        for dims in [vec![5], vec![2, 2], vec![2, 3], vec![2, 4], vec![2, 2, 2]] {
+            let valid = permutations(leaf_count(&dims))
+                .into_iter()
+                .filter(|order| Folding::new(order.clone()).is_valid(&dims))
+                .count();
+            assert_eq!(valid as i64, StampFolder::calculate_sequence(&dims), "{:?}", dims);
+        }
+
+        assert!(!Folding::new(vec![1, 2, 3]).is_valid(&[2, 2]));
+        assert!(!Folding::new(vec![1, 2, 2, 4]).is_valid(&[2, 2]));
+        assert!(!Folding::new(vec![1, 3, 2, 5]).is_valid(&[2, 2]));
+    }
+
+    #[test]
+    fn test_search_stacks_and_rotations() {
+        let dims = [2, 3];
+        let mut counts = SubtreeCounts::new(&dims);
+        let mut stacks = Vec::new();
+        let mut pending = vec![Vec::new()];
+        while let Some(prefix) = pending.pop() {
+            if prefix.len() == counts.leaves() {
+                if counts.count(&prefix) > 0 {
+                    stacks.push(Folding::from_gaps(&prefix));
+                }
+                continue;
+            }
+            for m in counts.children(&prefix) {
+                pending.push(prefix.iter().copied().chain([m]).collect());
+            }
+        }
+
+        let mut all: Vec<Folding> = stacks.iter().flat_map(|stack| (0..6).map(|shift| stack.rotated(shift))).collect();
+        assert!(stacks.iter().all(|stack| stack.order[0] == 1));
+        assert!(all.iter().all(|folding| folding.is_valid(&dims)));
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len() as i64, StampFolder::calculate_sequence(&dims));
+        assert_eq!(counts.count(&[]), all.len() as i64);
+
+        let folding: Folding = "1 2 4 3".parse().unwrap();
+        assert_eq!(folding.to_string(), "1 2 4 3");
+        assert_eq!(folding.rotated(5).order, vec![2, 4, 3, 1]);
+        assert_eq!(coordinates(&[2, 3], 6), vec![1, 2]);
+        assert!("1 x".parse::<Folding>().is_err());
+    }
+}
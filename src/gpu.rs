@@ -1,29 +1,79 @@
+use tracing::Instrument;
 use wgpu::util::DeviceExt;
 
+use crate::cpu;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 struct Params {
     dim: u32,
     n: u32,
-    mod_val: u32,
-    res: i32,
+    unit_count: u32,
+    unit_stride: u32,
+    state_stride: u32,
+    table_stride: u32,
+    max_steps: u32,
+    first_pass: u32,
+}
+
+// Matches @workgroup_size in shader.wgsl
+const WORKGROUP_SIZE: u32 = 64;
+// Units per dispatch at most; the device's storage binding limit may allow fewer
+const MAX_BATCH: usize = 8192;
+// Words of per-unit state before the gap stack (STATE_GAP in shader.wgsl)
+const STATE_GAP: u32 = 258;
+// Loop iterations one invocation may run per pass. llvmpipe ends any invocation after 65535
+// loop iterations in total, so passes stay below that and the host dispatches again.
+const LOOP_BUDGET: u32 = 60000;
+// Aim for at least this many subtrees when splitting a whole search
+const TARGET_UNITS: usize = 4096;
+
+/// One invocation's worth of work.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkUnit {
+    /// `res/mod` filter applied at level `mod_val` (0 for none).
+    pub mod_val: u32,
+    pub res: i32,
+    /// Gap choices of the first leaves, as from `cpu::StampFolder::subtree_prefixes`.
+    pub prefix: Vec<i32>,
+}
+
+impl WorkUnit {
+    pub fn residue(mod_val: u32, res: i32) -> Self {
+        WorkUnit { mod_val, res, prefix: Vec::new() }
+    }
+
+    pub fn subtree(prefix: Vec<i32>) -> Self {
+        WorkUnit { mod_val: 0, res: 0, prefix }
+    }
 }
 
 pub struct StampFolder {
     pipeline: wgpu::ComputePipeline,
-    bind_group: wgpu::BindGroup,
-    params_buffer: wgpu::Buffer,
-    p_array_buffer: wgpu::Buffer,
-    count_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    table_buffer: wgpu::Buffer,
+    dimensions: Vec<i32>,
+    steps_per_pass: u32,
 }
 
 impl StampFolder {
-    pub async fn new(device: &wgpu::Device, dimensions: &[i32], mod_val: u32, res: i32) -> Self {
+    pub fn new(device: &wgpu::Device, dimensions: &[i32]) -> Self {
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Stamp Folding Compute Shader"),
             source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
         });
 
+        let storage = |binding: u32, read_only: bool| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
         // Create bind group layout
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Stamp Folding Bind Group Layout"),
@@ -38,26 +88,10 @@ impl StampFolder {
                     },
                     count: None,
                 },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
+                storage(1, true),  // d table
+                storage(2, true),  // work units
+                storage(3, false), // per-unit search state
+                storage(4, false), // per-unit results
             ],
         });
 
@@ -80,48 +114,127 @@ impl StampFolder {
         let n: i32 = dimensions.iter().product();
         assert!(n < 64, "Dimension too large: product must be less than 64");
 
-        // Create params
-        let params = Params {
-            dim: dimensions.len() as u32,
-            n: n as u32,
-            mod_val,
-            res,
-        };
+        let table_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("D Table Buffer"),
+            contents: bytemuck::cast_slice(&cpu::StampFolder::connection_table(dimensions)),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
 
-        println!("Creating params: {:?}", params);
+        // Worst case per search step: the candidate loops in process_gaps, the filter loop
+        // and one backtrack
+        let dim = dimensions.len() as u32;
+        let per_step = (2 * dim + 4) * (n.max(1) as u32 + 1) + 4;
+        let steps_per_pass = ((LOOP_BUDGET - STATE_GAP - 2 * n as u32) / per_step).max(1);
 
-        // Create a reference to params as a slice
-        let params_slice = std::slice::from_ref(&params);
-        println!("Params as slice bytes: {:?}", bytemuck::bytes_of(&params));
+        tracing::debug!(?dimensions, steps_per_pass, "GPU pipeline ready");
+        Self {
+            pipeline,
+            bind_group_layout,
+            table_buffer,
+            dimensions: dimensions.to_vec(),
+            steps_per_pass,
+        }
+    }
+
+    /// Overrides the search steps each invocation runs per dispatch. Hardware GPUs can take far
+    /// more than the default, which is sized for software adapters.
+    pub fn with_steps_per_pass(mut self, steps: u32) -> Self {
+        // Each step finds at most one folding worth n, and a pass's count is a single u32
+        let n = self.dimensions.iter().product::<i32>().max(1) as u32;
+        self.steps_per_pass = steps.clamp(1, u32::MAX / n);
+        self
+    }
+
+    /// Searches every unit, one invocation each, and returns the per-unit counts.
+    pub async fn compute_units(&self, device: &wgpu::Device, queue: &wgpu::Queue, units: &[WorkUnit]) -> Vec<u64> {
+        let mut counts = Vec::with_capacity(units.len());
+        for batch in units.chunks(self.batch_size(device)) {
+            let span = tracing::debug_span!("batch", units = batch.len());
+            counts.extend(self.compute_batch(device, queue, batch, &vec![0; batch.len()]).instrument(span).await);
+        }
+        counts
+    }
+
+    // Words of search state per unit: the whole gap stack plus one level of candidates
+    fn state_stride(&self) -> u32 {
+        let n = self.dimensions.iter().product::<i32>() as u32;
+        STATE_GAP + (n + 1) * (n + 3)
+    }
+
+    // Units whose search state fits in one storage binding of `device`
+    fn batch_size(&self, device: &wgpu::Device) -> usize {
+        let limits = device.limits();
+        let max_binding = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        (max_binding / (self.state_stride() as u64 * 4)).clamp(1, MAX_BATCH as u64) as usize
+    }
+
+    // Each unit's count starts from `start`. Passes add their u32 partial sums into a (low, high)
+    // pair with carry, so per-unit counts are exact up to 2^64.
+    async fn compute_batch(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        units: &[WorkUnit],
+        start: &[u64],
+    ) -> Vec<u64> {
+        let n = self.dimensions.iter().product::<i32>() as u32;
+        let unit_stride = 3 + n;
+        let state_stride = self.state_stride();
+
+        let mut params = Params {
+            dim: self.dimensions.len() as u32,
+            n,
+            unit_count: units.len() as u32,
+            unit_stride,
+            state_stride,
+            table_stride: n + 1,
+            max_steps: self.steps_per_pass,
+            first_pass: 1,
+        };
+
+        let mut unit_data = vec![0i32; units.len() * unit_stride as usize];
+        for (unit, slot) in units.iter().zip(unit_data.chunks_mut(unit_stride as usize)) {
+            assert!(unit.prefix.len() < n.max(1) as usize, "prefix must leave at least one leaf to place");
+            slot[0] = unit.mod_val as i32;
+            slot[1] = unit.res;
+            slot[2] = unit.prefix.len() as i32;
+            slot[3..3 + unit.prefix.len()].copy_from_slice(&unit.prefix);
+        }
 
-        // Main params buffer for shader use
         let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Params Buffer"),
             contents: bytemuck::cast_slice(&[params]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_SRC,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let mut padded_dimensions = vec![0i32; 64];
-        padded_dimensions[..dimensions.len()].copy_from_slice(dimensions);
-        println!("Padded dimensions: {:?}", &padded_dimensions[..dimensions.len()]);
+        let units_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Work Units Buffer"),
+            contents: bytemuck::cast_slice(&unit_data),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
 
-        let p_array_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("P Array Buffer"),
-            contents: bytemuck::cast_slice(&padded_dimensions),
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        let state_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Search State Buffer"),
+            size: (units.len() as u64) * (state_stride as u64) * 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
         });
 
-        // Initialize count buffer with zeros
-        let count_buffer_data = vec![0i32; 64];
-        let count_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Count Buffer"),
-            contents: bytemuck::cast_slice(&count_buffer_data),
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        // A running-unit counter and a spare word, then two u32 words (low, high) per unit
+        let mut result_words = vec![0u32; 2];
+        for &count in start {
+            result_words.extend([count as u32, (count >> 32) as u32]);
+        }
+        let results_size = (result_words.len() * 4) as u64;
+        let results_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Results Buffer"),
+            contents: bytemuck::cast_slice(&result_words),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
         });
 
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Stamp Folding Bind Group"),
-            layout: &bind_group_layout,
+            layout: &self.bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
@@ -129,151 +242,178 @@ impl StampFolder {
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: p_array_buffer.as_entire_binding(),
+                    resource: self.table_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: count_buffer.as_entire_binding(),
+                    resource: units_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: state_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: results_buffer.as_entire_binding(),
                 },
             ],
         });
 
-        Self {
-            pipeline,
-            bind_group,
-            params_buffer,
-            p_array_buffer,
-            count_buffer,
-        }
-    }
-
-    pub async fn compute(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> i64 {
-        // Submit compute pass
-        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Compute Encoder"),
-        });
-
-        {
-            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                label: Some("Stamp Folding Compute Pass"),
-                timestamp_writes: None,
-            });
-            compute_pass.set_pipeline(&self.pipeline);
-            compute_pass.set_bind_group(0, &self.bind_group, &[]);
-            compute_pass.dispatch_workgroups(1, 1, 1);
-        }
-
-        queue.submit(Some(encoder.finish()));
-        device.poll(wgpu::Maintain::Wait); // Wait for compute to finish
-
-        // Create staging buffers
-        let params_staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Params Staging Buffer"),
-            size: std::mem::size_of::<Params>() as u64,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-
         let results_staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Results Staging Buffer"),
-            size: self.count_buffer.size(),
+            size: results_size,
             usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
-        // Copy data to staging buffers
-        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Copy Encoder"),
-        });
-
-        encoder.copy_buffer_to_buffer(
-            &self.params_buffer,
-            0,
-            &params_staging_buffer,
-            0,
-            params_staging_buffer.size()
-        );
-
-        encoder.copy_buffer_to_buffer(
-            &self.count_buffer,
-            0,
-            &results_staging_buffer,
-            0,
-            results_staging_buffer.size()
-        );
+        // Dispatch passes until no unit is still running; only the counter is read in between
+        for pass in 0.. {
+            queue.write_buffer(&params_buffer, 0, bytemuck::cast_slice(&[params]));
+            queue.write_buffer(&results_buffer, 0, bytemuck::cast_slice(&[0u32]));
 
-        queue.submit(Some(encoder.finish()));
-        device.poll(wgpu::Maintain::Wait); // Wait for copies to finish
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Compute Encoder"),
+            });
 
-        // Read parameters
-        let params_slice = params_staging_buffer.slice(..);
-        let (params_sender, params_receiver) = futures_intrusive::channel::shared::oneshot_channel();
-        params_slice.map_async(wgpu::MapMode::Read, move |result| {
-            let _ = params_sender.send(result);
-        });
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Stamp Folding Compute Pass"),
+                    timestamp_writes: None,
+                });
+                compute_pass.set_pipeline(&self.pipeline);
+                compute_pass.set_bind_group(0, &bind_group, &[]);
+                compute_pass.dispatch_workgroups((units.len() as u32).div_ceil(WORKGROUP_SIZE), 1, 1);
+            }
+
+            encoder.copy_buffer_to_buffer(&results_buffer, 0, &results_staging_buffer, 0, 4);
+            queue.submit(Some(encoder.finish()));
+
+            let running = read_words(device, &results_staging_buffer, 4).await[0];
+            tracing::trace!(pass, running, "pass finished");
+            if running == 0 {
+                break;
+            }
+            params.first_pass = 0;
+        }
 
-        let results_slice = results_staging_buffer.slice(..);
-        let (results_sender, results_receiver) = futures_intrusive::channel::shared::oneshot_channel();
-        results_slice.map_async(wgpu::MapMode::Read, move |result| {
-            let _ = results_sender.send(result);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Readback Encoder"),
         });
+        encoder.copy_buffer_to_buffer(&results_buffer, 0, &results_staging_buffer, 0, results_size);
+        queue.submit(Some(encoder.finish()));
 
-        device.poll(wgpu::Maintain::Wait);
+        let counts: Vec<u64> = read_words(device, &results_staging_buffer, results_size).await[2..]
+            .chunks(2)
+            .map(|pair| pair[0] as u64 | (pair[1] as u64) << 32)
+            .collect();
+        for (unit, count) in units.iter().zip(&counts) {
+            tracing::trace_span!("unit", mod_val = unit.mod_val, res = unit.res, prefix = ?unit.prefix)
+                .in_scope(|| tracing::trace!(count, "unit finished"));
+        }
+        counts
+    }
 
-        // Wait for both mappings to complete
-        if params_receiver.receive().await.unwrap().is_ok() {
-            let params_data = params_slice.get_mapped_range();
-            let params: &[Params] = bytemuck::cast_slice(&*params_data);
-            println!("Shader params: {:?}", params[0]);
+    /// Whole search, split into subtrees so every unit gets its own invocation.
+    pub async fn calculate_sequence_on(device: &wgpu::Device, queue: &wgpu::Queue, dimensions: &[i32]) -> u128 {
+        // Special case: if any dimension is 0, return 1
+        if dimensions.contains(&0) {
+            return 1;
         }
 
-        if results_receiver.receive().await.unwrap().is_ok() {
-            let data = results_slice.get_mapped_range();
-            let result: Vec<i32> = bytemuck::cast_slice(&data).to_vec();
-            println!("Raw results: {:?}", &result[..64]);
-            result.iter().take(64).map(|&x| x as i64).sum()
-        } else {
-            0
+        let n = dimensions.iter().product::<i32>() as usize;
+        let mut depth = 0;
+        let mut prefixes = vec![Vec::new()];
+        while prefixes.len() < TARGET_UNITS && depth + 1 < n {
+            depth += 1;
+            prefixes = cpu::StampFolder::subtree_prefixes(dimensions, depth);
         }
+
+        tracing::debug!(depth, units = prefixes.len(), "split into subtrees");
+        let units: Vec<WorkUnit> = prefixes.into_iter().map(WorkUnit::subtree).collect();
+        let folder = StampFolder::new(device, dimensions);
+        folder.compute_units(device, queue, &units).await.iter().map(|&c| c as u128).sum()
     }
 
-    pub async fn calculate_sequence(dimensions: &[i32]) -> i64 {
-        // Special case: if any dimension is 0, return 1
-        if dimensions.iter().any(|&d| d == 0) {
-            return 1;
+    pub async fn calculate_sequence(dimensions: &[i32]) -> u128 {
+        let (device, queue) = request_device(false).await.expect("no suitable GPU adapter");
+        Self::calculate_sequence_on(&device, &queue, dimensions).await
+    }
+
+    // Same split as cpu::StampFolder::calculate_sequence_part; all residues go out in one dispatch
+    pub async fn calculate_sequence_part(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        dimensions: &[i32],
+        part: usize,
+        total_parts: usize,
+    ) -> u128 {
+        if dimensions.contains(&0) {
+            return if part == 0 { 1 } else { 0 };
         }
 
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
-        let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::HighPerformance,
-            force_fallback_adapter: false,
-            compatible_surface: None,
-        })
-            .await
-            .unwrap();
-
-        let (device, queue) = adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                label: None,
-                required_features: wgpu::Features::empty(),
-                required_limits: wgpu::Limits::downlevel_defaults(),
-            },
-            None
-        ).await.unwrap();
+        let span = tracing::debug_span!("part", ?dimensions, part, total_parts);
+        // A single part is the whole search; residue filtering does not apply at l = 1
+        if total_parts == 1 {
+            return Self::calculate_sequence_on(device, queue, dimensions).instrument(span).await;
+        }
+
+        let n = dimensions.iter().product::<i32>() as usize;
+        let units: Vec<WorkUnit> = (part..n)
+            .step_by(total_parts)
+            .map(|res| WorkUnit::residue(total_parts as u32, res as i32))
+            .collect();
+        let folder = span.in_scope(|| StampFolder::new(device, dimensions));
+        folder.compute_units(device, queue, &units).instrument(span).await.iter().map(|&c| c as u128).sum()
+    }
+}
 
-        println!("\nProcessing dimensions: {:?}", dimensions);
-        let compute = StampFolder::new(&device, dimensions, 0, 0).await;
-        compute.compute(&device, &queue).await
+// Maps the first `size` bytes of a staging buffer and copies them out
+async fn read_words(device: &wgpu::Device, buffer: &wgpu::Buffer, size: u64) -> Vec<u32> {
+    let slice = buffer.slice(..size);
+    let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+
+    device.poll(wgpu::Maintain::Wait); // Wait for compute and copy to finish
+
+    match receiver.receive().await {
+        Some(Ok(())) => {
+            let words = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+            buffer.unmap();
+            words
+        }
+        _ => panic!("failed to read back GPU results"),
     }
 }
 
+/// Opens the first suitable adapter; `force_fallback_adapter` asks for a software implementation.
+pub async fn request_device(force_fallback_adapter: bool) -> Option<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        force_fallback_adapter,
+        compatible_surface: None,
+    })
+        .await?;
+
+    adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: None,
+            required_features: wgpu::Features::empty(),
+            required_limits: wgpu::Limits::downlevel_defaults(),
+        },
+        None
+    ).await.ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[tokio::test]
     async fn test_sequence_n_2() {
-        let expected = vec![
+        let expected = [
             1, 2, 8, 60, 320, 1980, 10512, 60788, 320896,
             1787904, 9381840, 51081844
         ];
@@ -291,4 +431,72 @@ mod tests {
             );
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_units_match_cpu_on_fallback_adapter() {
+        let (device, queue) = request_device(true).await.expect("no fallback adapter");
+        let dimensions = [3, 3];
+        let folder = StampFolder::new(&device, &dimensions);
+
+        let prefixes = cpu::StampFolder::subtree_prefixes(&dimensions, 3);
+        let units: Vec<WorkUnit> = prefixes.iter().cloned().map(WorkUnit::subtree).collect();
+        let counts = folder.compute_units(&device, &queue, &units).await;
+        for (prefix, count) in prefixes.iter().zip(counts) {
+            assert_eq!(count, cpu::StampFolder::count_subtree(&dimensions, prefix).count as u64);
+        }
+
+        let residues: Vec<WorkUnit> = (0..5).map(|res| WorkUnit::residue(5, res)).collect();
+        let counts = folder.compute_units(&device, &queue, &residues).await;
+        for (res, count) in counts.into_iter().enumerate() {
+            assert_eq!(count, cpu::StampFolder::calculate_sequence_part(&dimensions, res, 5) as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_counts_carry_past_32_bits() {
+        let (device, queue) = request_device(true).await.expect("no fallback adapter");
+        let dimensions = [2, 4];
+        let folder = StampFolder::new(&device, &dimensions).with_steps_per_pass(3);
+
+        // Start just below the 2^32 and 2^33 boundaries so later passes carry into the high word
+        let units: Vec<WorkUnit> = (0..2).map(|res| WorkUnit::residue(2, res)).collect();
+        let start = [u32::MAX as u64 - 10, (2u64 << 32) - 1];
+        let counts = folder.compute_batch(&device, &queue, &units, &start).await;
+        for (res, (count, start)) in counts.into_iter().zip(start).enumerate() {
+            let expected = cpu::StampFolder::calculate_sequence_part(&dimensions, res, 2) as u64;
+            assert_eq!(count, start + expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_fit_the_storage_binding() {
+        let (device, _) = request_device(true).await.expect("no fallback adapter");
+        let limit = device.limits().max_storage_buffer_binding_size as usize;
+        for dimensions in [[3, 3], [7, 9]] {
+            let folder = StampFolder::new(&device, &dimensions);
+            let batch = folder.batch_size(&device);
+            assert!((1..=MAX_BATCH).contains(&batch));
+            assert!(batch * folder.state_stride() as usize * 4 <= limit, "{:?}: {} units", dimensions, batch);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_totals_match_cpu_on_fallback_adapter() {
+        let (device, queue) = request_device(true).await.expect("no fallback adapter");
+        for dimensions in [[2, 6], [3, 4], [4, 4]] {
+            let expected = cpu::StampFolder::calculate_sequence(&dimensions) as u128;
+            assert_eq!(StampFolder::calculate_sequence_on(&device, &queue, &dimensions).await, expected);
+
+            let parts: u128 = sum_of_parts(&device, &queue, &dimensions, 3).await;
+            assert_eq!(parts, expected, "{:?} in 3 parts", dimensions);
+        }
+    }
+
+    async fn sum_of_parts(device: &wgpu::Device, queue: &wgpu::Queue, dimensions: &[i32], parts: usize) -> u128 {
+        let mut total = 0;
+        for part in 0..parts {
+            total += StampFolder::calculate_sequence_part(device, queue, dimensions, part, parts).await;
+        }
+        total
+    }
+}
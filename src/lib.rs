@@ -1,2 +1,51 @@
+//! Counting foldings of p1×…×pd maps with Lunnon's algorithm.
+//!
+//! Cargo features: `cpu` (default) builds the CPU solver and the tooling around it, `cli`
+//! (default) the `folds` binary, `async` the tokio facade and `gpu` the wgpu backend.
+//!
+//! Diagnostics go through `tracing` (spans per part and work unit); nothing in the library
+//! prints. The binary installs a subscriber, see `logging`.
+
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod backend;
+#[cfg(feature = "cpu")]
+pub mod boinc;
+#[cfg(feature = "cpu")]
+pub mod certificate;
+#[cfg(feature = "cpu")]
 pub mod cpu;
-pub mod gpu;
\ No newline at end of file
+#[cfg(feature = "cpu")]
+pub mod diff;
+#[cfg(feature = "cpu")]
+pub mod enumerate;
+#[cfg(feature = "cpu")]
+pub mod fold_file;
+#[cfg(feature = "cpu")]
+pub mod folding;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+#[cfg(feature = "cli")]
+pub mod logging;
+#[cfg(feature = "cpu")]
+pub mod merge;
+#[cfg(feature = "cpu")]
+pub mod mv;
+#[cfg(feature = "cpu")]
+pub mod plan;
+#[cfg(feature = "cpu")]
+pub mod polyomino;
+#[cfg(feature = "cpu")]
+pub mod profile;
+#[cfg(feature = "cpu")]
+pub mod rank;
+#[cfg(feature = "cpu")]
+pub mod render;
+#[cfg(feature = "cpu")]
+mod rng;
+#[cfg(feature = "cpu")]
+pub mod sample;
+#[cfg(feature = "cpu")]
+pub mod simple;
+#[cfg(feature = "cpu")]
+pub mod stats;
@@ -0,0 +1,97 @@
+//! Log output for the `folds` binary. Logs go to stderr so stdout stays machine-readable.
+//!
+//! `--log-level` takes a level or an `EnvFilter` directive (`debug`, `folds::gpu=trace`) and
+//! falls back to `FOLDS_LOG`, then `warn`. `--log-format json` writes one JSON object per event,
+//! for worker daemons whose logs are collected.
+
+use tracing_subscriber::EnvFilter;
+
+pub const LEVEL_ENV: &str = "FOLDS_LOG";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogOptions {
+    /// `None` reads `FOLDS_LOG`.
+    pub filter: Option<String>,
+    pub format: LogFormat,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions { filter: None, format: LogFormat::Text }
+    }
+}
+
+/// Takes `--log-level` and `--log-format` out of the arguments, wherever they appear.
+pub fn split_log_options(args: Vec<String>) -> Result<(Vec<String>, LogOptions), String> {
+    let mut options = LogOptions::default();
+    let mut rest = Vec::new();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        let name = match arg.as_str() {
+            "--log-level" | "--log-format" => arg,
+            _ => {
+                rest.push(arg);
+                continue;
+            }
+        };
+        let value = iter.next().ok_or_else(|| format!("{} needs a value", name))?;
+        if name == "--log-level" {
+            EnvFilter::try_new(&value).map_err(|e| format!("invalid --log-level `{}`: {}", value, e))?;
+            options.filter = Some(value);
+        } else {
+            options.format = match value.as_str() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                _ => return Err(format!("invalid --log-format `{}` (expected text or json)", value)),
+            };
+        }
+    }
+    Ok((rest, options))
+}
+
+/// Installs the global subscriber; fails if one is already set.
+pub fn init(options: &LogOptions) -> Result<(), String> {
+    let filter = match &options.filter {
+        Some(filter) => EnvFilter::try_new(filter).map_err(|e| e.to_string())?,
+        None => EnvFilter::try_from_env(LEVEL_ENV).unwrap_or_else(|_| EnvFilter::new("warn")),
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    let result = match options.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_log_options() {
+        let (rest, options) =
+            split_log_options(args(&["merge", "--log-level", "folds=debug", "out", "--log-format", "json"])).unwrap();
+        assert_eq!(rest, args(&["merge", "out"]));
+        assert_eq!(options.filter.as_deref(), Some("folds=debug"));
+        assert_eq!(options.format, LogFormat::Json);
+
+        let (rest, options) = split_log_options(args(&["3", "3", "--backend", "cpu"])).unwrap();
+        assert_eq!(rest, args(&["3", "3", "--backend", "cpu"]));
+        assert_eq!(options, LogOptions::default());
+
+        assert!(split_log_options(args(&["--log-format", "xml"])).is_err());
+        assert!(split_log_options(args(&["--log-level"])).is_err());
+        assert!(split_log_options(args(&["--log-level", "folds=loud"])).is_err());
+    }
+}
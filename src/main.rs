@@ -1,14 +1,121 @@
 use std::env;
-use folds::cpu::StampFolder;
+use std::fs;
+use std::io;
+use std::process;
+use std::path::Path;
+use std::time::Duration;
+use folds::backend::{self, FoldRequest};
+use folds::boinc;
+use folds::certificate::{self, Certificate, Leaf};
+use folds::diff::{self, DiffOptions};
+use folds::enumerate;
+use folds::fold_file::{FoldReader, Symmetry};
+use folds::folding::Folding;
+use folds::logging;
+use folds::merge::{self, MergeError};
+use folds::mv::MvPattern;
+use folds::plan::{Plan, PlanOptions};
+use folds::polyomino::Polyomino;
+use folds::profile::TreeProfile;
+use folds::rank::Ranker;
+use folds::render;
+use folds::sample;
+use folds::simple;
+use folds::stats::StatsPolynomial;
 
 fn main() {
-    let args: Vec<String> = env::args().skip(1).collect();
+    let (args, log_options) = logging::split_log_options(env::args().skip(1).collect()).unwrap_or_else(|e| fail(&e));
+    logging::init(&log_options).unwrap_or_else(|e| fail(&e));
 
     if args.is_empty() {
-        println!("Usage: [res/mod] dimension...");
+        println!("Usage: [res/mod] dimension... [--record dir] [--backend cpu|gpu]");
+        println!("       merge <dir> [--certificate file]");
+        println!("       boinc [--slot dir] [--checkpoint-secs s]");
+        println!("       certify <parts> dimension... [--out file]");
+        println!("       verify-certificate <file> [--samples k] [--seed s]");
+        println!("       profile [res/mod] dimension... [--format table|json]");
+        println!("       sample <k> dimension... [--seed s]");
+        println!("       rank dimension... --folding \"leaf...\"");
+        println!("       unrank <index> dimension...");
+        println!("       polynomial dimension...");
+        println!("       mv dimension... --folding \"leaf...\"");
+        println!("       mv-count <pattern-file>");
+        println!("       mv-list <pattern-file>");
+        println!("       simple <pattern-file>");
+        println!("       simple-count dimension...");
+        println!("       enumerate dimension... [--symmetry all|rotations] [--out file.fold]");
+        println!("       diff <a.fold> <b.fold> [--memory foldings] [--examples k]");
+        println!("       render dimension... --folding \"leaf...\" [--format ascii|svg] [--out file]");
+        println!("       shape-count <map-file>");
+        println!("       plan <workers> <target-secs> dimension... [--probes k] [--seed s] [--rate nodes/s] [--workunits dir]");
+        println!("Every command takes --log-level <level|filter> and --log-format text|json (logs go to stderr).");
         return;
     }
 
+    match args[0].as_str() {
+        "certify" => certify(&args[1..]),
+        "verify-certificate" => verify_certificate(&args[1..]),
+        "merge" => merge_results(&args[1..]),
+        "boinc" => run_boinc(&args[1..]),
+        "profile" => profile(&args[1..]),
+        "plan" => plan(&args[1..]),
+        "sample" => sample_foldings(&args[1..]),
+        "rank" => rank(&args[1..]),
+        "unrank" => unrank(&args[1..]),
+        "polynomial" => polynomial(&args[1..]),
+        "mv" => mv_pattern(&args[1..]),
+        "mv-count" => mv_count(&args[1..]),
+        "mv-list" => mv_list(&args[1..]),
+        "simple" => simple_folds(&args[1..]),
+        "simple-count" => simple_count(&args[1..]),
+        "shape-count" => shape_count(&args[1..]),
+        "enumerate" => enumerate_foldings(&args[1..]),
+        "diff" => diff_files(&args[1..]),
+        "render" => render_folding(&args[1..]),
+        _ => count(&args),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+// Splits `--name value` options off the positional arguments
+fn split_options<'a>(args: &'a [String], names: &[&str]) -> (Vec<&'a str>, Vec<(&'a str, &'a str)>) {
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if let Some(name) = arg.strip_prefix("--") {
+            if !names.contains(&name) {
+                fail(&format!("unknown option --{}", name));
+            }
+            match iter.next() {
+                Some(value) => options.push((name, value.as_str())),
+                None => fail(&format!("--{} needs a value", name)),
+            }
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+    (positional, options)
+}
+
+// Dimensions of a map the search can take on
+fn parse_dimensions(args: &[&str]) -> Vec<i32> {
+    let dimensions: Vec<i32> = args
+        .iter()
+        .map(|s| s.parse::<i32>().unwrap_or_else(|_| fail(&format!("invalid dimension `{}`", s))))
+        .collect();
+    if let Err(e) = certificate::check_dims(&dimensions) {
+        fail(&e.to_string());
+    }
+    dimensions
+}
+
+// An optional leading `res/mod`, then the dimensions
+fn parse_part_and_dimensions(args: &[&str]) -> (i32, i32, Vec<i32>) {
     let (res, mod_val, args_used) = if args[0].contains('/') {
         let parts: Vec<&str> = args[0].split('/').collect();
         (
@@ -20,20 +127,408 @@ fn main() {
         (0, 0, 0)
     };
 
-    let dimensions: Vec<i32> = args
-        .iter()
-        .skip(args_used)
-        .map(|s| s.parse::<i32>().unwrap())
-        .collect();
+    (res, mod_val, parse_dimensions(&args[args_used..]))
+}
 
-    if mod_val == 0 {
+fn count(args: &[String]) {
+    let (args, options) = split_options(args, &["record", "backend"]);
+    if args.is_empty() {
+        fail("usage: [res/mod] dimension... [--record dir] [--backend cpu|gpu]");
+    }
+
+    let (res, mod_val, dimensions) = parse_part_and_dimensions(&args);
+    let option = |wanted: &str| options.iter().find(|(name, _)| *name == wanted).map(|(_, value)| *value);
+    let record_dir = option("record");
+    let backend_name = option("backend").unwrap_or("cpu");
+
+    if let Some(dir) = record_dir {
+        if mod_val == 0 {
+            fail("--record needs a res/mod part");
+        }
+        if backend_name != "cpu" {
+            fail("--record needs the cpu backend");
+        }
+        let leaf = Leaf::compute(&dimensions, res as usize, mod_val as usize);
+        println!("{}", leaf.count);
+        merge::write_record(Path::new(dir), &leaf).unwrap_or_else(|e| fail(&format!("{}: {}", dir, e)));
+        return;
+    }
+
+    let request = if mod_val == 0 {
         // Use parallel processing with number of threads based on CPU count
-        let num_threads = num_cpus::get();
-        let result = StampFolder::calculate_sequence_parallel(&dimensions, num_threads);
-        println!("{}", result);
+        let mut request = FoldRequest::whole(&dimensions);
+        request.options.threads = Some(num_cpus::get());
+        request
     } else {
         // Calculate specific part as requested
-        let result = StampFolder::calculate_sequence_part(&dimensions, res as usize, mod_val as usize);
-        println!("{}", result);
+        FoldRequest::part(&dimensions, res as usize, mod_val as usize)
+    };
+
+    let backend = backend::backend(backend_name).unwrap_or_else(|e| fail(&e.to_string()));
+    let result = backend.count(&request).unwrap_or_else(|e| fail(&e.to_string()));
+    println!("{}", result.count);
+}
+
+fn certify(args: &[String]) {
+    let (positional, options) = split_options(args, &["out"]);
+    if positional.len() < 2 {
+        fail("usage: certify <parts> dimension... [--out file]");
+    }
+
+    let parts: usize = positional[0]
+        .parse()
+        .unwrap_or_else(|_| fail(&format!("invalid part count `{}`", positional[0])));
+    let dimensions = parse_dimensions(&positional[1..]);
+
+    let certificate = Certificate::compute(&dimensions, parts).unwrap_or_else(|e| fail(&e.to_string()));
+    match options.iter().find(|(name, _)| *name == "out") {
+        Some((_, path)) => {
+            fs::write(path, certificate.to_text()).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+            println!("{}", certificate.total);
+        }
+        None => print!("{}", certificate.to_text()),
+    }
+}
+
+fn verify_certificate(args: &[String]) {
+    let (positional, options) = split_options(args, &["samples", "seed"]);
+    if positional.len() != 1 {
+        fail("usage: verify-certificate <file> [--samples k] [--seed s]");
+    }
+
+    let mut samples = 16;
+    let mut seed = 0;
+    for (name, value) in options {
+        let parsed: u64 = value.parse().unwrap_or_else(|_| fail(&format!("invalid --{} `{}`", name, value)));
+        match name {
+            "samples" => samples = parsed as usize,
+            _ => seed = parsed,
+        }
+    }
+
+    let path = positional[0];
+    let text = fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let certificate = Certificate::parse(&text).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    match certificate.verify(samples, seed) {
+        Ok(verification) => {
+            println!(
+                "ok: total {} over {} parts, recomputed parts {:?}",
+                verification.total, certificate.total_parts, verification.sampled_parts
+            );
+        }
+        Err(e) => fail(&format!("{}: {}", path, e)),
+    }
+}
+
+fn merge_results(args: &[String]) {
+    let (positional, options) = split_options(args, &["certificate"]);
+    if positional.len() != 1 {
+        fail("usage: merge <dir> [--certificate file]");
+    }
+
+    let dir = positional[0];
+    let mut records = Vec::new();
+    for (path, record) in merge::read_records(Path::new(dir)).unwrap_or_else(|e| fail(&format!("{}: {}", dir, e))) {
+        match record {
+            Ok(leaf) => records.push(leaf),
+            Err(e) => fail(&format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    let merged = match merge::merge(records) {
+        Ok(merged) => merged,
+        Err(MergeError::Incomplete { missing }) => {
+            eprintln!("incomplete: {} parts missing", missing.len());
+            println!("missing {}", merge::format_ranges(&missing));
+            process::exit(2);
+        }
+        Err(e) => fail(&e.to_string()),
+    };
+
+    if !merged.duplicates.is_empty() {
+        tracing::warn!(parts = %merge::format_ranges(&merged.duplicates), "identical duplicate records");
+    }
+    if let Some((_, path)) = options.iter().find(|(name, _)| *name == "certificate") {
+        fs::write(path, merged.certificate().to_text()).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    }
+    println!("{}", merged.total);
+}
+
+fn run_boinc(args: &[String]) {
+    let (positional, options) = split_options(args, &["slot", "checkpoint-secs"]);
+    if !positional.is_empty() {
+        fail("usage: boinc [--slot dir] [--checkpoint-secs s]");
+    }
+
+    let mut slot = ".";
+    let mut boinc_options = boinc::Options::default();
+    for (name, value) in options {
+        match name {
+            "slot" => slot = value,
+            _ => {
+                let secs: u64 = value.parse().unwrap_or_else(|_| fail(&format!("invalid --{} `{}`", name, value)));
+                boinc_options.checkpoint_period = Duration::from_secs(secs);
+            }
+        }
+    }
+
+    match boinc::run(Path::new(slot), &boinc_options) {
+        Ok(leaf) => println!("{}", leaf.count),
+        Err(e) => fail(&format!("{}: {}", slot, e)),
+    }
+}
+
+fn profile(args: &[String]) {
+    let (args, options) = split_options(args, &["format"]);
+    if args.is_empty() {
+        fail("usage: profile [res/mod] dimension... [--format table|json]");
+    }
+
+    let (res, mod_val, dimensions) = parse_part_and_dimensions(&args);
+    let (part, total_parts) = if mod_val == 0 { (0, 1) } else { (res as usize, mod_val as usize) };
+    if let Err(e) = certificate::check_partition(&dimensions, total_parts) {
+        fail(&e.to_string());
+    }
+    if part >= total_parts {
+        fail(&format!("part {} is outside the partition", part));
+    }
+
+    let profile = TreeProfile::compute(&dimensions, part, total_parts);
+    match options.iter().find(|(name, _)| *name == "format").map_or("table", |(_, value)| *value) {
+        "table" => print!("{}", profile.to_table()),
+        "json" => println!("{}", profile.to_json()),
+        other => fail(&format!("invalid --format `{}` (expected table or json)", other)),
+    }
+}
+
+fn plan(args: &[String]) {
+    let (args, options) = split_options(args, &["probes", "seed", "rate", "workunits"]);
+    if args.len() < 3 {
+        fail("usage: plan <workers> <target-secs> dimension... [--probes k] [--seed s] [--rate nodes/s] [--workunits dir]");
+    }
+
+    let workers: usize = args[0].parse().unwrap_or_else(|_| fail(&format!("invalid worker count `{}`", args[0])));
+    let target: f64 = args[1].parse().unwrap_or_else(|_| fail(&format!("invalid target `{}`", args[1])));
+    if workers == 0 || !(target > 0.0 && target.is_finite()) {
+        fail("the worker count and target must be positive");
+    }
+    let dimensions = parse_dimensions(&args[2..]);
+
+    let mut plan_options = PlanOptions { workers, target: Duration::from_secs_f64(target), ..PlanOptions::default() };
+    let mut workunit_dir = None;
+    for (name, value) in options {
+        match name {
+            "workunits" => workunit_dir = Some(value),
+            "probes" => plan_options.probes = value.parse().unwrap_or_else(|_| fail(&format!("invalid --probes `{}`", value))),
+            "seed" => plan_options.seed = value.parse().unwrap_or_else(|_| fail(&format!("invalid --seed `{}`", value))),
+            _ => match value.parse::<f64>() {
+                Ok(rate) if rate > 0.0 => plan_options.nodes_per_second = Some(rate),
+                _ => fail(&format!("invalid --rate `{}`", value)),
+            },
+        }
+    }
+
+    let plan = Plan::compute(&dimensions, &plan_options);
+    if let Some(dir) = workunit_dir {
+        let write_units = || -> io::Result<()> {
+            fs::create_dir_all(dir)?;
+            for unit in &plan.units {
+                let path = Path::new(dir).join(format!("wu_{}", unit.part));
+                fs::write(path, boinc::workunit_to_text(&plan.unit_spec(unit)))?;
+            }
+            Ok(())
+        };
+        write_units().unwrap_or_else(|e| fail(&format!("{}: {}", dir, e)));
+    }
+    print!("{}", plan.to_text());
+}
+
+fn sample_foldings(args: &[String]) {
+    let (args, options) = split_options(args, &["seed"]);
+    if args.len() < 2 {
+        fail("usage: sample <k> dimension... [--seed s]");
+    }
+
+    let k: usize = args[0].parse().unwrap_or_else(|_| fail(&format!("invalid sample count `{}`", args[0])));
+    let dimensions = parse_dimensions(&args[1..]);
+    let seed = options.first().map_or(0, |(_, value)| {
+        value.parse().unwrap_or_else(|_| fail(&format!("invalid --seed `{}`", value)))
+    });
+    for folding in sample::sample_foldings(&dimensions, k, seed) {
+        println!("{}", folding);
+    }
+}
+
+fn rank(args: &[String]) {
+    let (args, options) = split_options(args, &["folding"]);
+    let Some((_, leaves)) = options.first() else {
+        fail("usage: rank dimension... --folding \"leaf...\"");
+    };
+    let dimensions = parse_dimensions(&args);
+    let folding = leaves.parse().unwrap_or_else(|e: String| fail(&e));
+    match Ranker::new(&dimensions).rank(&folding) {
+        Some(index) => println!("{}", index),
+        None => fail(&format!("`{}` is not a folding of {:?}", folding, dimensions)),
+    }
+}
+
+fn unrank(args: &[String]) {
+    let (args, _) = split_options(args, &[]);
+    if args.len() < 2 {
+        fail("usage: unrank <index> dimension...");
+    }
+
+    let index: u128 = args[0].parse().unwrap_or_else(|_| fail(&format!("invalid index `{}`", args[0])));
+    let dimensions = parse_dimensions(&args[1..]);
+    let mut ranker = Ranker::new(&dimensions);
+    match ranker.unrank(index) {
+        Some(folding) => println!("{}", folding),
+        None => fail(&format!("index {} is past the {} foldings of {:?}", index, ranker.total(), dimensions)),
+    }
+}
+
+fn polynomial(args: &[String]) {
+    let (args, _) = split_options(args, &[]);
+    if args.is_empty() {
+        fail("usage: polynomial dimension...");
+    }
+    print!("{}", StatsPolynomial::compute(&parse_dimensions(&args)).to_text());
+}
+
+fn mv_pattern(args: &[String]) {
+    let (args, options) = split_options(args, &["folding"]);
+    let Some((_, leaves)) = options.first() else {
+        fail("usage: mv dimension... --folding \"leaf...\"");
+    };
+    let dimensions = parse_dimensions(&args);
+    let folding: Folding = leaves.parse().unwrap_or_else(|e: String| fail(&e));
+    if !folding.is_valid(&dimensions) {
+        fail(&format!("`{}` is not a folding of {:?}", folding, dimensions));
+    }
+    print!("{}", MvPattern::of(&dimensions, &folding).to_text());
+}
+
+fn read_pattern(args: &[String], usage: &str) -> MvPattern {
+    let (args, _) = split_options(args, &[]);
+    if args.len() != 1 {
+        fail(usage);
+    }
+    let text = fs::read_to_string(args[0]).unwrap_or_else(|e| fail(&format!("{}: {}", args[0], e)));
+    MvPattern::parse(&text).unwrap_or_else(|e| fail(&format!("{}: {}", args[0], e)))
+}
+
+fn mv_count(args: &[String]) {
+    println!("{}", read_pattern(args, "usage: mv-count <pattern-file>").count_foldings());
+}
+
+fn mv_list(args: &[String]) {
+    read_pattern(args, "usage: mv-list <pattern-file>").for_each_folding(|folding| println!("{}", folding));
+}
+
+fn simple_folds(args: &[String]) {
+    let pattern = read_pattern(args, "usage: simple <pattern-file>");
+    let Some(folds) = simple::simple_foldable(&pattern) else {
+        fail("the pattern cannot be folded by simple folds");
+    };
+    for fold in &folds {
+        println!("{}", fold);
+    }
+    if let Some(folding) = simple::apply_simple_folds(&pattern.dims, &folds) {
+        println!("stack {}", folding);
+    }
+}
+
+fn simple_count(args: &[String]) {
+    let (args, _) = split_options(args, &[]);
+    if args.is_empty() {
+        fail("usage: simple-count dimension...");
+    }
+    println!("{}", simple::simple_foldings(&parse_dimensions(&args)).len());
+}
+
+fn render_folding(args: &[String]) {
+    let (args, options) = split_options(args, &["folding", "format", "out"]);
+    let option = |wanted: &str| options.iter().find(|(name, _)| *name == wanted).map(|(_, value)| *value);
+    let Some(leaves) = option("folding") else {
+        fail("usage: render dimension... --folding \"leaf...\" [--format ascii|svg] [--out file]");
+    };
+    let dimensions = parse_dimensions(&args);
+    if dimensions.contains(&0) {
+        fail(&format!("{:?} has no leaves to render", dimensions));
+    }
+    let folding: Folding = leaves.parse().unwrap_or_else(|e: String| fail(&e));
+    if !folding.is_valid(&dimensions) {
+        fail(&format!("`{}` is not a folding of {:?}", folding, dimensions));
+    }
+
+    let text = match option("format").unwrap_or("ascii") {
+        "ascii" => render::render_ascii(&dimensions, &folding),
+        "svg" => render::render_svg(&dimensions, &folding),
+        other => fail(&format!("invalid --format `{}` (expected ascii or svg)", other)),
+    };
+    match option("out") {
+        Some(path) => fs::write(path, text).unwrap_or_else(|e| fail(&format!("{}: {}", path, e))),
+        None => print!("{}", text),
+    }
+}
+
+fn shape_count(args: &[String]) {
+    let (args, _) = split_options(args, &[]);
+    if args.len() != 1 {
+        fail("usage: shape-count <map-file>");
+    }
+    let text = fs::read_to_string(args[0]).unwrap_or_else(|e| fail(&format!("{}: {}", args[0], e)));
+    let map = Polyomino::parse(&text).unwrap_or_else(|e| fail(&format!("{}: {}", args[0], e)));
+    println!("{}", map.count_foldings());
+}
+
+fn enumerate_foldings(args: &[String]) {
+    let (args, options) = split_options(args, &["symmetry", "out"]);
+    if args.is_empty() {
+        fail("usage: enumerate dimension... [--symmetry all|rotations] [--out file.fold]");
+    }
+    let dimensions = parse_dimensions(&args);
+    let option = |wanted: &str| options.iter().find(|(name, _)| *name == wanted).map(|(_, value)| *value);
+    let symmetry = match option("symmetry").unwrap_or("all") {
+        "all" => Symmetry::All,
+        "rotations" => Symmetry::Rotations,
+        other => fail(&format!("invalid --symmetry `{}` (expected all or rotations)", other)),
+    };
+
+    match option("out") {
+        Some(path) => {
+            let file = fs::File::create(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+            let (_, records) = enumerate::write_foldings(&dimensions, symmetry, io::BufWriter::new(file))
+                .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+            println!("{}", records);
+        }
+        None if symmetry == Symmetry::All => enumerate::for_each_folding(&dimensions, |folding| println!("{}", folding)),
+        None => fail("--symmetry rotations needs --out"),
+    }
+}
+
+fn diff_files(args: &[String]) {
+    let (paths, options) = split_options(args, &["memory", "examples"]);
+    if paths.len() != 2 {
+        fail("usage: diff <a.fold> <b.fold> [--memory foldings] [--examples k]");
+    }
+    let mut diff_options = DiffOptions::default();
+    for (name, value) in options {
+        let parsed = value.parse().unwrap_or_else(|_| fail(&format!("invalid --{} `{}`", name, value)));
+        match name {
+            "memory" => diff_options.run_foldings = parsed,
+            _ => diff_options.examples = parsed,
+        }
+    }
+
+    let open = |path: &str| {
+        let file = fs::File::open(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        FoldReader::open(io::BufReader::new(file)).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)))
+    };
+    let (mut a, mut b) = (open(paths[0]), open(paths[1]));
+    let report = diff::diff(&mut a, &mut b, &diff_options).unwrap_or_else(|e| fail(&e.to_string()));
+    print!("{}", report.to_text([paths[0], paths[1]]));
+    if !report.is_clean() {
+        process::exit(1);
     }
 }
@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::certificate::{check_partition, Certificate, Leaf, UnitSpec};
+
+pub const RECORD_HEADER: &str = "folds-result v1";
+
+/// Serializes one part result in the line format read back by `parse_record`.
+pub fn record_to_text(leaf: &Leaf) -> String {
+    let dims: Vec<String> = leaf.spec.dims.iter().map(|d| d.to_string()).collect();
+    format!(
+        "{}\nversion {}\ndims {}\npart {}\nparts {}\ncount {}\nnodes {}\n",
+        RECORD_HEADER,
+        leaf.version,
+        dims.join(" "),
+        leaf.spec.part,
+        leaf.spec.total_parts,
+        leaf.count,
+        leaf.nodes
+    )
+}
+
+pub fn parse_record(text: &str) -> Result<Leaf, String> {
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    if lines.next() != Some(RECORD_HEADER) {
+        return Err("not a folds result record".to_string());
+    }
+
+    let mut fields = BTreeMap::new();
+    for line in lines {
+        let (name, value) = line.split_once(' ').ok_or_else(|| format!("malformed line `{}`", line))?;
+        if fields.insert(name, value.trim()).is_some() {
+            return Err(format!("field `{}` given twice", name));
+        }
+    }
+
+    let field = |name: &str| fields.get(name).copied().ok_or_else(|| format!("missing `{}`", name));
+    let number = |name: &str| -> Result<u64, String> {
+        field(name)?.parse().map_err(|_| format!("invalid `{}`", name))
+    };
+
+    let dims = field("dims")?
+        .split_whitespace()
+        .map(|d| d.parse())
+        .collect::<Result<Vec<i32>, _>>()
+        .map_err(|_| "invalid `dims`".to_string())?;
+    let count = field("count")?.parse().map_err(|_| "invalid `count`".to_string())?;
+
+    Ok(Leaf {
+        spec: UnitSpec { dims, part: number("part")? as usize, total_parts: number("parts")? as usize },
+        count,
+        nodes: number("nodes")?,
+        version: field("version")?.to_string(),
+    })
+}
+
+/// File name used by the part runner, so one directory can hold a whole partition.
+pub fn record_file_name(spec: &UnitSpec) -> String {
+    let dims: Vec<String> = spec.dims.iter().map(|d| d.to_string()).collect();
+    format!("{}-{}-of-{}.result", dims.join("x"), spec.part, spec.total_parts)
+}
+
+pub fn write_record(dir: &Path, leaf: &Leaf) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(record_file_name(&leaf.spec));
+    fs::write(&path, record_to_text(leaf))?;
+    Ok(path)
+}
+
+/// Reads every `*.result` file in `dir`, in file name order.
+pub fn read_records(dir: &Path) -> io::Result<Vec<(PathBuf, Result<Leaf, String>)>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "result"));
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let record = fs::read_to_string(&path)?;
+            Ok((path, parse_record(&record)))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeError {
+    NoRecords,
+    /// Records disagree on what problem they belong to.
+    Mismatch { field: &'static str, expected: String, found: String },
+    InvalidPartition { dims: Vec<i32>, total_parts: usize },
+    PartOutOfRange(usize),
+    /// The same part was reported with different results.
+    Conflict { part: usize, counts: Vec<i64> },
+    Incomplete { missing: Vec<usize> },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::NoRecords => write!(f, "no result records found"),
+            MergeError::Mismatch { field, expected, found } => {
+                write!(f, "records disagree on {}: {} vs {}", field, expected, found)
+            }
+            MergeError::InvalidPartition { dims, total_parts } => {
+                write!(f, "cannot split {:?} into {} parts", dims, total_parts)
+            }
+            MergeError::PartOutOfRange(part) => write!(f, "part {} is outside the partition", part),
+            MergeError::Conflict { part, counts } => {
+                write!(f, "part {} was reported with different results: {:?}", part, counts)
+            }
+            MergeError::Incomplete { missing } => write!(f, "missing parts: {}", format_ranges(missing)),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Renders sorted part numbers compactly, e.g. `0-3,7,9-10`.
+pub fn format_ranges(parts: &[usize]) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < parts.len() {
+        let start = parts[i];
+        while i + 1 < parts.len() && parts[i + 1] == parts[i] + 1 {
+            i += 1;
+        }
+        ranges.push(if parts[i] == start { start.to_string() } else { format!("{}-{}", start, parts[i]) });
+        i += 1;
+    }
+    ranges.join(",")
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Merged {
+    pub dims: Vec<i32>,
+    pub total_parts: usize,
+    pub version: String,
+    pub total: i64,
+    /// Parts that were reported more than once with identical results.
+    pub duplicates: Vec<usize>,
+    /// One record per part, sorted by part.
+    pub leaves: Vec<Leaf>,
+}
+
+impl Merged {
+    pub fn certificate(&self) -> Certificate {
+        Certificate::from_leaves(&self.dims, self.total_parts, self.leaves.clone())
+            .expect("merged records cover the partition")
+    }
+}
+
+/// Combines part results, requiring every part of one partition exactly once (identical repeats are tolerated).
+pub fn merge(records: Vec<Leaf>) -> Result<Merged, MergeError> {
+    let first = records.first().ok_or(MergeError::NoRecords)?;
+    let (dims, total_parts, version) = (first.spec.dims.clone(), first.spec.total_parts, first.version.clone());
+
+    for record in &records {
+        if record.spec.dims != dims {
+            return Err(MergeError::Mismatch {
+                field: "dims",
+                expected: format!("{:?}", dims),
+                found: format!("{:?}", record.spec.dims),
+            });
+        }
+        if record.spec.total_parts != total_parts {
+            return Err(MergeError::Mismatch {
+                field: "parts",
+                expected: total_parts.to_string(),
+                found: record.spec.total_parts.to_string(),
+            });
+        }
+        if record.version != version {
+            return Err(MergeError::Mismatch { field: "version", expected: version, found: record.version.clone() });
+        }
+        if record.spec.part >= total_parts {
+            return Err(MergeError::PartOutOfRange(record.spec.part));
+        }
+    }
+    check_partition(&dims, total_parts).map_err(|_| MergeError::InvalidPartition { dims: dims.clone(), total_parts })?;
+
+    let mut by_part: BTreeMap<usize, Vec<Leaf>> = BTreeMap::new();
+    for record in records {
+        by_part.entry(record.spec.part).or_default().push(record);
+    }
+
+    let mut duplicates = Vec::new();
+    let mut leaves = Vec::with_capacity(by_part.len());
+    for (part, mut reports) in by_part {
+        if reports.iter().any(|r| r.count != reports[0].count || r.nodes != reports[0].nodes) {
+            return Err(MergeError::Conflict { part, counts: reports.iter().map(|r| r.count).collect() });
+        }
+        if reports.len() > 1 {
+            tracing::debug!(part, copies = reports.len(), "duplicate record");
+            duplicates.push(part);
+        }
+        leaves.push(reports.swap_remove(0));
+    }
+
+    let missing: Vec<usize> = (0..total_parts)
+        .filter(|part| leaves.binary_search_by_key(part, |leaf| leaf.spec.part).is_err())
+        .collect();
+    if !missing.is_empty() {
+        tracing::debug!(missing = missing.len(), "merge incomplete");
+        return Err(MergeError::Incomplete { missing });
+    }
+
+    Ok(Merged {
+        dims,
+        total_parts,
+        version,
+        total: leaves.iter().map(|leaf| leaf.count).sum(),
+        duplicates,
+        leaves,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(dims: &[i32], total_parts: usize) -> Vec<Leaf> {
+        (0..total_parts).map(|part| Leaf::compute(dims, part, total_parts)).collect()
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let leaf = Leaf::compute(&[2, 3], 1, 4);
+        assert_eq!(parse_record(&record_to_text(&leaf)), Ok(leaf));
+        assert!(parse_record("folds-result v1\ndims 2 3\n").is_err());
+    }
+
+    #[test]
+    fn test_merge_complete_partition() {
+        let mut parts = records(&[3, 3], 7);
+        parts.push(parts[4].clone());
+        parts.reverse();
+
+        let merged = merge(parts).unwrap();
+        assert_eq!(merged.total, 1368);
+        assert_eq!(merged.duplicates, vec![4]);
+        assert_eq!(merged.certificate().total, 1368);
+    }
+
+    #[test]
+    fn test_merge_reports_problems() {
+        let mut parts = records(&[2, 4], 8);
+        parts.remove(6);
+        parts.remove(2);
+        parts.remove(1);
+        assert_eq!(merge(parts.clone()), Err(MergeError::Incomplete { missing: vec![1, 2, 6] }));
+        assert_eq!(format_ranges(&[1, 2, 6]), "1-2,6");
+
+        let mut conflicting = parts[0].clone();
+        conflicting.count += 1;
+        parts.push(conflicting);
+        assert!(matches!(merge(parts.clone()), Err(MergeError::Conflict { part: 0, .. })));
+
+        let mut other = records(&[2, 4], 4);
+        other.extend(records(&[2, 4], 8));
+        assert!(matches!(merge(other), Err(MergeError::Mismatch { field: "parts", .. })));
+
+        assert_eq!(merge(Vec::new()), Err(MergeError::NoRecords));
+    }
+
+    #[test]
+    fn test_records_in_directory() {
+        let dir = std::env::temp_dir().join(format!("folds-merge-test-{}", std::process::id()));
+        for leaf in records(&[2, 2], 3) {
+            write_record(&dir, &leaf).unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
+
+        let read: Vec<Leaf> = read_records(&dir).unwrap().into_iter().map(|(_, r)| r.unwrap()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read.len(), 3);
+        assert_eq!(merge(read).unwrap().total, 8);
+    }
+}
@@ -0,0 +1,295 @@
+//! Mountain-valley patterns: a fold for some or all creases of the map, read off a folding or
+//! given in a small text format, and the foldings that realize one, found by a search that
+//! prunes against the pattern.
+//!
+//! The text format gives each axis one line of rows, a row being the creases along the axis
+//! that start at one leaf with coordinate 0 on it, in leaf order:
+//!
+//! ```text
+//! folds-mv v1
+//! dims 3 2
+//! axis 1 MV VM
+//! axis 2 M V ?
+//! ```
+//!
+//! `M` is a mountain, `V` a valley and `?` leaves the crease free.
+
+use std::fmt::Write;
+
+use crate::cpu::StampFolder;
+use crate::folding::{coordinates, leaf_count, Fold, Folding};
+
+pub const MV_HEADER: &str = "folds-mv v1";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MvPattern {
+    pub dims: Vec<i32>,
+    // folds[axis][leaf - 1]: the crease between leaf and the next leaf along axis, if assigned
+    folds: Vec<Vec<Option<Fold>>>,
+}
+
+impl MvPattern {
+    /// A pattern with every crease free.
+    pub fn free(dims: &[i32]) -> Self {
+        MvPattern { dims: dims.to_vec(), folds: vec![vec![None; leaf_count(dims)]; dims.len()] }
+    }
+
+    /// The folds `folding` gives every crease.
+    pub fn of(dims: &[i32], folding: &Folding) -> Self {
+        let mut pattern = MvPattern::free(dims);
+        for crease in folding.creases(dims) {
+            pattern.folds[crease.axis][(crease.leaf - 1) as usize] = Some(crease.fold);
+        }
+        pattern
+    }
+
+    /// The fold of the crease between `leaf` and the next leaf along `axis`, if assigned.
+    pub fn fold(&self, axis: usize, leaf: i32) -> Option<Fold> {
+        self.folds[axis][(leaf - 1) as usize]
+    }
+
+    /// Assigns a crease; panics if `leaf` is the last along `axis`.
+    pub fn set(&mut self, axis: usize, leaf: i32, fold: Option<Fold>) {
+        assert!(self.has_crease(axis, leaf), "leaf {} has no crease after it along axis {}", leaf, axis + 1);
+        self.folds[axis][(leaf - 1) as usize] = fold;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        (0..self.dims.len()).all(|axis| self.rows(axis).iter().flatten().all(|&leaf| self.fold(axis, leaf).is_some()))
+    }
+
+    /// Whether `folding` folds every assigned crease as assigned.
+    pub fn accepts(&self, folding: &Folding) -> bool {
+        folding.creases(&self.dims).iter().all(|crease| {
+            self.fold(crease.axis, crease.leaf).is_none_or(|fold| fold == crease.fold)
+        })
+    }
+
+    /// Foldings of the map that realize the pattern. The search runs over stacks as they are
+    /// (no leaf-1-on-top normalization), dropping every gap that puts a leaf on the wrong side of
+    /// a neighbour across an assigned crease.
+    pub fn count_foldings(&self) -> u64 {
+        let n = leaf_count(&self.dims);
+        if n == 0 {
+            return 1;
+        }
+        let _span = tracing::debug_span!("mv count", dims = ?self.dims).entered();
+        let mut folder = self.constrained_search();
+        folder.resume(u64::MAX);
+        // Each completed stack counts as n, the rotations of a normalized one
+        folder.count as u64 / n as u64
+    }
+
+    /// Calls `visit` with every folding that realizes the pattern, in search order.
+    pub fn for_each_folding(&self, mut visit: impl FnMut(&Folding)) {
+        if leaf_count(&self.dims) == 0 {
+            visit(&Folding::new(Vec::new()));
+            return;
+        }
+        let mut folding = Folding::new(Vec::new());
+        self.constrained_search().visit_stacks(|stack| {
+            folding.order.clear();
+            folding.order.extend_from_slice(stack);
+            visit(&folding);
+        });
+    }
+
+    fn constrained_search(&self) -> StampFolder {
+        let mut folder = StampFolder::new();
+        folder.start(&self.dims, false, 0, 0);
+        folder.set_order_constraints(self.order_constraints());
+        folder
+    }
+
+    // For each leaf v, (u, u above v) for the assigned creases to its smaller neighbours u. A
+    // valley keeps the front faces together: v lies above u exactly when u faces up.
+    fn order_constraints(&self) -> Vec<Vec<(i32, bool)>> {
+        let mut constraints = vec![Vec::new(); leaf_count(&self.dims) + 1];
+        for axis in 0..self.dims.len() {
+            let stride = self.stride(axis);
+            for u in self.rows(axis).into_iter().flatten() {
+                if let Some(fold) = self.fold(axis, u) {
+                    let front_up = coordinates(&self.dims, u).iter().sum::<i32>() % 2 == 0;
+                    let v_above = (fold == Fold::Valley) == front_up;
+                    constraints[(u + stride) as usize].push((u, !v_above));
+                }
+            }
+        }
+        constraints
+    }
+
+    fn stride(&self, axis: usize) -> i32 {
+        self.dims[..axis].iter().product()
+    }
+
+    fn has_crease(&self, axis: usize, leaf: i32) -> bool {
+        let p = self.dims[axis];
+        leaf >= 1 && leaf as usize <= leaf_count(&self.dims) && (leaf - 1) / self.stride(axis) % p + 1 < p
+    }
+
+    // The leaves starting each crease along `axis`, row by row
+    fn rows(&self, axis: usize) -> Vec<Vec<i32>> {
+        let (p, stride) = (self.dims[axis], self.stride(axis));
+        (1..=leaf_count(&self.dims) as i32)
+            .filter(|&leaf| (leaf - 1) / stride % p == 0)
+            .map(|start| (0..p - 1).map(|x| start + x * stride).collect())
+            .collect()
+    }
+
+    pub fn to_text(&self) -> String {
+        let dims: Vec<String> = self.dims.iter().map(|d| d.to_string()).collect();
+        let mut text = format!("{}\ndims {}\n", MV_HEADER, dims.join(" "));
+        for axis in 0..self.dims.len() {
+            let rows: Vec<String> = self
+                .rows(axis)
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|&leaf| match self.fold(axis, leaf) {
+                            Some(Fold::Mountain) => 'M',
+                            Some(Fold::Valley) => 'V',
+                            None => '?',
+                        })
+                        .collect()
+                })
+                .collect();
+            let _ = writeln!(text, "axis {} {}", axis + 1, rows.join(" "));
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        if lines.next() != Some(MV_HEADER) {
+            return Err(format!("missing `{}` header", MV_HEADER));
+        }
+        let dims: Vec<i32> = lines
+            .next()
+            .and_then(|line| line.strip_prefix("dims "))
+            .ok_or("missing `dims` line")?
+            .split_whitespace()
+            .map(|d| d.parse().ok().filter(|&d| d > 0))
+            .collect::<Option<_>>()
+            .ok_or("invalid `dims`")?;
+        if dims.is_empty() || leaf_count(&dims) >= 64 {
+            return Err("invalid `dims`".to_string());
+        }
+
+        let mut pattern = MvPattern::free(&dims);
+        let mut seen = vec![false; dims.len()];
+        for line in lines {
+            let mut fields = line.split_whitespace();
+            let axis = match (fields.next(), fields.next().and_then(|axis| axis.parse::<usize>().ok())) {
+                (Some("axis"), Some(axis)) if (1..=dims.len()).contains(&axis) && !seen[axis - 1] => axis - 1,
+                _ => return Err(format!("invalid line `{}`", line)),
+            };
+            seen[axis] = true;
+
+            let rows = pattern.rows(axis);
+            let given: Vec<&str> = fields.collect();
+            if given.len() != rows.len() {
+                return Err(format!("axis {}: expected {} rows, found {}", axis + 1, rows.len(), given.len()));
+            }
+            for (row, folds) in rows.iter().zip(given) {
+                if folds.chars().count() != row.len() {
+                    return Err(format!("axis {}: row `{}` should have {} creases", axis + 1, folds, row.len()));
+                }
+                for (&leaf, c) in row.iter().zip(folds.chars()) {
+                    let fold = match c {
+                        'M' => Some(Fold::Mountain),
+                        'V' => Some(Fold::Valley),
+                        '?' => None,
+                        _ => return Err(format!("axis {}: invalid fold `{}`", axis + 1, c)),
+                    };
+                    pattern.set(axis, leaf, fold);
+                }
+            }
+        }
+        if let Some(axis) = seen.iter().position(|&seen| !seen) {
+            return Err(format!("missing axis {}", axis + 1));
+        }
+        Ok(pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enumerate::for_each_folding;
+    use crate::rng::SplitMix64;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_patterns_of_foldings() {
+        let dims = [2, 3];
+        let mut realized: HashMap<MvPattern, u64> = HashMap::new();
+        for_each_folding(&dims, |folding| {
+            let pattern = MvPattern::of(&dims, folding);
+            assert!(pattern.is_complete() && pattern.accepts(folding));
+            *realized.entry(pattern).or_default() += 1;
+        });
+        assert_eq!(realized.values().sum::<u64>() as i64, StampFolder::calculate_sequence(&dims));
+        for (pattern, &count) in realized.iter().take(5) {
+            assert_eq!(pattern.count_foldings(), count);
+        }
+
+        // With nothing assigned every folding counts; a strip's single crease splits them evenly
+        assert_eq!(MvPattern::free(&dims).count_foldings() as i64, StampFolder::calculate_sequence(&dims));
+        let mut pattern = MvPattern::free(&[2]);
+        pattern.set(0, 1, Some(Fold::Valley));
+        assert_eq!(pattern.count_foldings(), 1);
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let folding = crate::rank::unrank(&[3, 2], 17).unwrap();
+        let pattern = MvPattern::of(&[3, 2], &folding);
+        assert_eq!(MvPattern::parse(&pattern.to_text()), Ok(pattern));
+
+        let text = "folds-mv v1\ndims 3 2\naxis 1 MV ?M\naxis 2 M ? V\n";
+        let pattern = MvPattern::parse(text).unwrap();
+        assert_eq!(pattern.to_text(), text);
+        assert_eq!(pattern.fold(0, 2), Some(Fold::Valley));
+        assert_eq!(pattern.fold(0, 4), None);
+        assert_eq!(pattern.fold(1, 3), Some(Fold::Valley));
+        assert!(!pattern.is_complete());
+
+        assert!(MvPattern::parse("folds-mv v1\ndims 3 2\naxis 1 MV VM\n").is_err());
+        assert!(MvPattern::parse("folds-mv v1\ndims 3 2\naxis 1 MV V\naxis 2 M V ?\n").is_err());
+        assert!(MvPattern::parse("folds-mv v1\ndims 3 2\naxis 1 MV VX\naxis 2 M V ?\n").is_err());
+    }
+
+    #[test]
+    fn test_pruned_search_matches_filtered_enumeration() {
+        let mut rng = SplitMix64::new(5);
+        for dims in [vec![6], vec![2, 4], vec![3, 3], vec![2, 2, 2]] {
+            let mut all = Vec::new();
+            for_each_folding(&dims, |folding| all.push(folding.clone()));
+            for _ in 0..20 {
+                // A third of the creases get a random fold, a sixth the fold of some folding
+                let source = MvPattern::of(&dims, &all[rng.below(all.len() as u64) as usize]);
+                let mut pattern = MvPattern::free(&dims);
+                for axis in 0..dims.len() {
+                    for leaf in pattern.rows(axis).into_iter().flatten() {
+                        let fold = match rng.below(6) {
+                            0 => Some(Fold::Mountain),
+                            1 => Some(Fold::Valley),
+                            2 => source.fold(axis, leaf),
+                            _ => None,
+                        };
+                        pattern.set(axis, leaf, fold);
+                    }
+                }
+
+                let expected: Vec<&Folding> = all.iter().filter(|folding| pattern.accepts(folding)).collect();
+                assert_eq!(pattern.count_foldings(), expected.len() as u64, "{}", pattern.to_text());
+                let mut found = Vec::new();
+                pattern.for_each_folding(|folding| found.push(folding.clone()));
+                found.sort();
+                let mut expected: Vec<Folding> = expected.into_iter().cloned().collect();
+                expected.sort();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Chooses how to split a count into `res/mod` parts: the number of parts and a predicted
+//! cost for each, from sampled estimates of the part sizes (Knuth's estimator) and a measured
+//! or given search rate.
+//!
+//! A plan's units are plain `part`/`parts` units, so they go straight into work units (see
+//! `boinc`), result records and `merge`, and together cover the whole search exactly once.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::certificate::UnitSpec;
+use crate::cpu::{PartSearch, StampFolder};
+use crate::rng::SplitMix64;
+
+pub const PLAN_HEADER: &str = "folds-plan v1";
+
+// Placements timed when no rate is given
+const CALIBRATION_NODES: u64 = 1 << 18;
+// Heaviest unit over the mean above which a plan counts as unbalanced
+const MAX_IMBALANCE: f64 = 1.5;
+
+/// Mean of `probes` random descents below `prefix`: an unbiased estimate of the number of
+/// placements `count_subtree(dims, prefix)` makes.
+pub fn estimate_nodes(dims: &[i32], prefix: &[i32], probes: usize, seed: u64) -> f64 {
+    let mut folder = StampFolder::new();
+    estimate_with(&mut folder, dims, prefix, probes, &mut SplitMix64::new(seed))
+}
+
+fn estimate_with(folder: &mut StampFolder, dims: &[i32], prefix: &[i32], probes: usize, rng: &mut SplitMix64) -> f64 {
+    if dims.is_empty() || dims.contains(&0) {
+        return 0.0;
+    }
+    folder.start_subtree(dims, prefix);
+    let probes = probes.max(1);
+    (0..probes).map(|_| folder.probe(rng)).sum::<f64>() / probes as f64
+}
+
+/// Estimate of the number of placements part `part` of `total_parts` makes, from `probes`
+/// random descents per residue.
+pub fn estimate_part_nodes(dims: &[i32], part: usize, total_parts: usize, probes: usize, seed: u64) -> f64 {
+    PartSearch::new(dims, part, total_parts).estimate_nodes(probes, &mut SplitMix64::new(seed))
+}
+
+/// Placements per second of a single-threaded search of `dims`, timed over a short run.
+pub fn measure_rate(dims: &[i32]) -> f64 {
+    let mut search = PartSearch::new(dims, 0, 1);
+    let started = Instant::now();
+    search.step(CALIBRATION_NODES);
+    search.stats().nodes.max(1) as f64 / started.elapsed().as_secs_f64().max(1e-9)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlanOptions {
+    pub workers: usize,
+    /// Wanted run time of one unit.
+    pub target: Duration,
+    /// Random descents per estimated residue.
+    pub probes: usize,
+    pub seed: u64,
+    /// Placements per second of one worker; `None` measures it.
+    pub nodes_per_second: Option<f64>,
+}
+
+impl Default for PlanOptions {
+    fn default() -> Self {
+        PlanOptions { workers: 1, target: Duration::from_secs(60), probes: 64, seed: 0, nodes_per_second: None }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedUnit {
+    pub part: usize,
+    pub predicted_nodes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub dims: Vec<i32>,
+    pub total_parts: usize,
+    pub nodes_per_second: f64,
+    /// One per part, in part order.
+    pub units: Vec<PlannedUnit>,
+    pub warnings: Vec<String>,
+}
+
+impl Plan {
+    /// Estimates the search and takes enough parts to keep each near `target` (and at least one
+    /// per worker): the fewest from there on whose heaviest part meets the target, or the
+    /// split with the lightest heaviest part when none does. A `res/mod` split has at most one
+    /// part per leaf.
+    pub fn compute(dims: &[i32], options: &PlanOptions) -> Plan {
+        let _span = tracing::debug_span!("plan", ?dims, workers = options.workers).entered();
+        let rate = options.nodes_per_second.unwrap_or_else(|| measure_rate(dims));
+        let mut rng = SplitMix64::new(options.seed);
+        let target = options.target.as_secs_f64().max(1e-9);
+
+        let total = PartSearch::new(dims, 0, 1).estimate_nodes(options.probes, &mut rng);
+        let wanted = ((total / rate / target).ceil() as usize).max(options.workers).max(1);
+        let n = dims.iter().product::<i32>().max(0) as usize;
+        let max_parts = n.max(1);
+
+        let mut best: Option<Vec<u64>> = None;
+        for total_parts in wanted.min(max_parts)..=max_parts {
+            let estimates: Vec<u64> = (0..total_parts)
+                .map(|part| {
+                    let mut search = PartSearch::new(dims, part, total_parts);
+                    search.estimate_nodes(options.probes, &mut rng).round() as u64
+                })
+                .collect();
+            let heaviest = |estimates: &[u64]| estimates.iter().copied().max().unwrap_or(0);
+            let fits = heaviest(&estimates) as f64 / rate <= target;
+            if best.as_ref().is_none_or(|best| heaviest(&estimates) < heaviest(best)) {
+                best = Some(estimates);
+            }
+            if fits {
+                break;
+            }
+        }
+        let estimates = best.unwrap_or_default();
+        tracing::debug!(total, wanted, parts = estimates.len(), "split chosen");
+
+        let units = estimates
+            .iter()
+            .enumerate()
+            .map(|(part, &predicted_nodes)| PlannedUnit { part, predicted_nodes })
+            .collect();
+        let mut plan = Plan {
+            dims: dims.to_vec(),
+            total_parts: estimates.len(),
+            nodes_per_second: rate,
+            units,
+            warnings: Vec::new(),
+        };
+        if wanted > max_parts {
+            plan.warnings.push(format!(
+                "the target needs {} units, but a res/mod split of {} leaves has at most {} parts",
+                wanted, n, max_parts
+            ));
+        }
+        if plan.imbalance() > MAX_IMBALANCE {
+            plan.warnings.push(format!(
+                "unbalanced: the heaviest unit is predicted at {:.2}x the mean",
+                plan.imbalance()
+            ));
+        }
+        let heaviest = estimates.iter().copied().max().unwrap_or(0);
+        if heaviest as f64 / rate > target {
+            plan.warnings.push(format!(
+                "the heaviest of {} parts is predicted at {:.3}s, over the {:.3}s target",
+                plan.total_parts,
+                heaviest as f64 / rate,
+                target
+            ));
+        }
+        for warning in &plan.warnings {
+            tracing::warn!(?dims, "{}", warning);
+        }
+        plan
+    }
+
+    /// The work unit that runs `unit`.
+    pub fn unit_spec(&self, unit: &PlannedUnit) -> UnitSpec {
+        UnitSpec { dims: self.dims.clone(), part: unit.part, total_parts: self.total_parts }
+    }
+
+    pub fn total_nodes(&self) -> u64 {
+        self.units.iter().map(|unit| unit.predicted_nodes).sum()
+    }
+
+    pub fn predicted_seconds(&self, unit: &PlannedUnit) -> f64 {
+        unit.predicted_nodes as f64 / self.nodes_per_second
+    }
+
+    /// Predicted nodes of the heaviest unit over the mean (1 is perfectly even).
+    pub fn imbalance(&self) -> f64 {
+        let total = self.total_nodes();
+        if self.units.is_empty() || total == 0 {
+            return 1.0;
+        }
+        let heaviest = self.units.iter().map(|unit| unit.predicted_nodes).max().unwrap_or(0);
+        heaviest as f64 * self.units.len() as f64 / total as f64
+    }
+
+    /// `unit <part> <nodes> <seconds>` lines, one per part of the `parts`-way split.
+    pub fn to_text(&self) -> String {
+        let dims: Vec<String> = self.dims.iter().map(|d| d.to_string()).collect();
+        let mut text = format!(
+            "{}\ndims {}\nparts {}\nrate {:.0}\n",
+            PLAN_HEADER,
+            dims.join(" "),
+            self.total_parts,
+            self.nodes_per_second
+        );
+        for warning in &self.warnings {
+            let _ = writeln!(text, "warning {}", warning);
+        }
+        for unit in &self.units {
+            let _ = writeln!(text, "unit {} {} {:.3}", unit.part, unit.predicted_nodes, self.predicted_seconds(unit));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boinc;
+    use crate::certificate::{Certificate, Leaf};
+
+    fn options(workers: usize, target: Duration, rate: f64) -> PlanOptions {
+        PlanOptions { workers, target, probes: 64, seed: 1, nodes_per_second: Some(rate) }
+    }
+
+    #[test]
+    fn test_estimate_is_close_to_the_search() {
+        for dims in [[3, 3], [2, 5]] {
+            let nodes = StampFolder::calculate_part_stats(&dims, 0, 1).nodes as f64;
+            let estimate = estimate_nodes(&dims, &[], 4000, 7);
+            assert!((estimate - nodes).abs() < 0.1 * nodes, "{:?}: {} vs {}", dims, estimate, nodes);
+        }
+
+        // A subtree's estimate is of that subtree alone
+        let prefix = &StampFolder::subtree_prefixes(&[3, 3], 3)[0];
+        let nodes = StampFolder::count_subtree(&[3, 3], prefix).nodes as f64;
+        let estimate = estimate_nodes(&[3, 3], prefix, 4000, 7);
+        assert!((estimate - nodes).abs() < 0.15 * nodes, "{} vs {}", estimate, nodes);
+
+        // A part's estimate covers all of its residues
+        for part in 0..3 {
+            let nodes = StampFolder::calculate_part_stats(&[3, 3], part, 3).nodes as f64;
+            let estimate = estimate_part_nodes(&[3, 3], part, 3, 4000, 7);
+            assert!((estimate - nodes).abs() < 0.15 * nodes, "part {}: {} vs {}", part, estimate, nodes);
+        }
+    }
+
+    #[test]
+    fn test_plan_units_run_as_work_units() {
+        for dims in [vec![3, 4], vec![2, 2, 2], vec![7]] {
+            let plan = Plan::compute(&dims, &options(3, Duration::from_secs(1), 1e6));
+            assert!(plan.total_parts >= 3);
+            let parts: Vec<usize> = plan.units.iter().map(|unit| unit.part).collect();
+            assert_eq!(parts, (0..plan.total_parts).collect::<Vec<_>>());
+
+            // Every unit goes through the work unit format and the partition is certified whole
+            let leaves: Vec<Leaf> = plan
+                .units
+                .iter()
+                .map(|unit| {
+                    let spec = boinc::parse_workunit(&boinc::workunit_to_text(&plan.unit_spec(unit))).unwrap();
+                    Leaf::compute(&spec.dims, spec.part, spec.total_parts)
+                })
+                .collect();
+            let certificate = Certificate::from_leaves(&dims, plan.total_parts, leaves).unwrap();
+            assert_eq!(certificate.total, StampFolder::calculate_sequence(&dims), "{:?}", dims);
+        }
+
+        let text = Plan::compute(&[3, 4], &options(3, Duration::from_secs(1), 1e6)).to_text();
+        assert!(text.starts_with("folds-plan v1\ndims 3 4\nparts "));
+        assert!(text.contains("\nunit 0 "));
+    }
+
+    #[test]
+    fn test_plan_sizes_units_and_warns() {
+        // At 100 nodes a second a 1s target needs several units
+        let total = estimate_nodes(&[3, 3], &[], 64, 1);
+        let plan = Plan::compute(&[3, 3], &options(1, Duration::from_secs(1), 100.0));
+        assert!(plan.total_parts >= (total / 100.0).ceil() as usize);
+        assert!(plan.total_parts > 1);
+
+        // Nothing splits fine enough for a nanosecond, and no split has more parts than leaves
+        let plan = Plan::compute(&[3, 3], &options(2, Duration::from_nanos(1), 1000.0));
+        assert_eq!(plan.total_parts, 9);
+        assert!(plan.warnings.iter().any(|warning| warning.contains("at most 9 parts")));
+        assert!(plan.warnings.iter().any(|warning| warning.contains("over the")));
+        assert!(plan.to_text().contains("\nwarning "));
+    }
+}
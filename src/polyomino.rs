@@ -0,0 +1,274 @@
+//! Maps that are not full boxes: any connected set of cells of a box, such as an L-shape, a
+//! cross or a rectangle with holes. The search only needs each leaf's neighbours along every
+//! axis and the parity of its coordinates (which edge of the stack its creases lie on), so a
+//! box is the special case with every cell present.
+//!
+//! Leaves are the cells that are present, numbered from 1 in box order with the first axis
+//! fastest. The text format is a bitmap of one- or two-axis maps, one row per line with `#` for
+//! a cell and `.` for none, the first axis along the rows:
+//!
+//! ```text
+//! folds-map v1
+//! ###
+//! #.#
+//! ###
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::cpu::StampFolder;
+use crate::folding::{nested_or_disjoint, Folding};
+
+pub const MAP_HEADER: &str = "folds-map v1";
+
+// Leaves the search can place; see `cpu`
+const MAX_LEAVES: usize = 63;
+
+/// The leaves of a map and how they meet, axis by axis.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeafAdjacency {
+    pub n: i32,
+    /// `lower[axis][leaf]`: the leaf one step back along the axis, 0 if there is none.
+    pub lower: Vec<Vec<i32>>,
+    /// `upper[axis][leaf]`: the leaf one step on along the axis, 0 if there is none.
+    pub upper: Vec<Vec<i32>>,
+    /// `odd[axis][leaf]`: whether the leaf's coordinate along the axis is odd.
+    pub odd: Vec<Vec<bool>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Polyomino {
+    /// The bounding box.
+    pub dims: Vec<i32>,
+    // Whether each cell of the box is part of the map, first axis fastest
+    cells: Vec<bool>,
+}
+
+impl Polyomino {
+    /// The full box, the map `StampFolder::start` folds.
+    pub fn rectangle(dims: &[i32]) -> Self {
+        let size = dims.iter().product::<i32>().max(0) as usize;
+        Polyomino { dims: dims.to_vec(), cells: vec![true; size] }
+    }
+
+    /// The cells of `dims` marked in `cells` (first axis fastest), which must be connected.
+    pub fn new(dims: &[i32], cells: Vec<bool>) -> Result<Self, String> {
+        if dims.is_empty() || dims.iter().any(|&d| d < 1) {
+            return Err(format!("invalid dimensions {:?}", dims));
+        }
+        if cells.len() != dims.iter().product::<i32>() as usize {
+            return Err(format!("{} cells for a {:?} box", cells.len(), dims));
+        }
+        let map = Polyomino { dims: dims.to_vec(), cells };
+        match map.leaf_count() {
+            0 => return Err("the map has no cells".to_string()),
+            n if n > MAX_LEAVES => return Err(format!("{} cells is more than the search handles ({})", n, MAX_LEAVES)),
+            _ => {}
+        }
+        if !map.is_connected() {
+            return Err("the cells are not connected".to_string());
+        }
+        Ok(map)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().map(str::trim_end).filter(|line| !line.is_empty());
+        if lines.next() != Some(MAP_HEADER) {
+            return Err(format!("missing `{}` header", MAP_HEADER));
+        }
+        let rows: Vec<&str> = lines.collect();
+        let width = rows.iter().map(|row| row.chars().count()).max().ok_or("missing rows")?;
+
+        let mut cells = Vec::with_capacity(width * rows.len());
+        for row in &rows {
+            for c in row.chars().chain(std::iter::repeat('.')).take(width) {
+                match c {
+                    '#' => cells.push(true),
+                    '.' => cells.push(false),
+                    _ => return Err(format!("invalid cell `{}`", c)),
+                }
+            }
+        }
+        let dims = if rows.len() == 1 { vec![width as i32] } else { vec![width as i32, rows.len() as i32] };
+        Polyomino::new(&dims, cells)
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.cells.iter().filter(|&&cell| cell).count()
+    }
+
+    /// Zero-based coordinates of each leaf, leaf 1 first.
+    pub fn leaves(&self) -> Vec<Vec<i32>> {
+        (0..self.cells.len()).filter(|&cell| self.cells[cell]).map(|cell| self.coordinates(cell)).collect()
+    }
+
+    fn coordinates(&self, mut cell: usize) -> Vec<i32> {
+        self.dims
+            .iter()
+            .map(|&p| {
+                let x = cell % p as usize;
+                cell /= p as usize;
+                x as i32
+            })
+            .collect()
+    }
+
+    pub fn adjacency(&self) -> LeafAdjacency {
+        // Leaf number of every cell of the box, 0 for the missing ones
+        let mut leaf_of = vec![0; self.cells.len()];
+        let mut n = 0;
+        for (cell, &present) in self.cells.iter().enumerate() {
+            if present {
+                n += 1;
+                leaf_of[cell] = n;
+            }
+        }
+
+        let table = vec![vec![0; n as usize + 1]; self.dims.len()];
+        let mut adjacency =
+            LeafAdjacency { n, lower: table.clone(), upper: table, odd: vec![vec![false; n as usize + 1]; self.dims.len()] };
+        for (cell, &leaf) in leaf_of.iter().enumerate().filter(|&(_, &leaf)| leaf != 0) {
+            let coordinates = self.coordinates(cell);
+            let mut stride = 1;
+            for (axis, &p) in self.dims.iter().enumerate() {
+                let x = coordinates[axis];
+                if x > 0 {
+                    adjacency.lower[axis][leaf as usize] = leaf_of[cell - stride];
+                }
+                if x + 1 < p {
+                    adjacency.upper[axis][leaf as usize] = leaf_of[cell + stride];
+                }
+                adjacency.odd[axis][leaf as usize] = x % 2 == 1;
+                stride *= p as usize;
+            }
+        }
+        adjacency
+    }
+
+    fn is_connected(&self) -> bool {
+        let adjacency = self.adjacency();
+        let n = adjacency.n as usize;
+        let mut seen = vec![false; n + 1];
+        let mut pending = VecDeque::from([1]);
+        seen[1] = true;
+        let mut reached = 1;
+        while let Some(leaf) = pending.pop_front() {
+            for next in adjacency.lower.iter().chain(&adjacency.upper).map(|neighbours| neighbours[leaf]) {
+                if next != 0 && !seen[next as usize] {
+                    seen[next as usize] = true;
+                    reached += 1;
+                    pending.push_back(next as usize);
+                }
+            }
+        }
+        reached == n
+    }
+
+    /// Whether `folding` is a flat folding of the map, by the rule `Folding::is_valid` uses for
+    /// boxes: creases after even and after odd coordinates lie on opposite edges of the stack,
+    /// and creases on one edge nest or are disjoint.
+    pub fn is_valid(&self, folding: &Folding) -> bool {
+        let n = self.leaf_count();
+        let mut sorted = folding.order.clone();
+        sorted.sort_unstable();
+        if !sorted.iter().copied().eq(1..=n as i32) {
+            return false;
+        }
+
+        let adjacency = self.adjacency();
+        let position = folding.positions();
+        (0..self.dims.len()).all(|axis| {
+            [false, true].iter().all(|&odd| {
+                let mut creases: Vec<(usize, usize)> = (1..=n)
+                    .filter(|&leaf| adjacency.upper[axis][leaf] != 0 && adjacency.odd[axis][leaf] == odd)
+                    .map(|leaf| {
+                        let (u, v) = (position[leaf], position[adjacency.upper[axis][leaf] as usize]);
+                        (u.min(v), u.max(v))
+                    })
+                    .collect();
+                nested_or_disjoint(&mut creases)
+            })
+        })
+    }
+
+    pub fn count_foldings(&self) -> i64 {
+        let _span = tracing::debug_span!("polyomino", dims = ?self.dims, n = self.leaf_count()).entered();
+        let mut folder = StampFolder::new();
+        folder.start_map(self, true, 0, 0);
+        folder.resume(u64::MAX);
+        folder.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every permutation with leaf 1 on top that is a flat folding, times the n rotations
+    fn brute_force(map: &Polyomino) -> i64 {
+        let n = map.leaf_count() as i32;
+        let mut rest: Vec<i32> = (2..=n).collect();
+        let mut count = 0;
+        permute(&mut rest, 0, &mut |rest| {
+            let order: Vec<i32> = [1].iter().chain(rest.iter()).copied().collect();
+            count += map.is_valid(&Folding::new(order)) as i64;
+        });
+        count * n as i64
+    }
+
+    fn permute(items: &mut [i32], k: usize, visit: &mut impl FnMut(&[i32])) {
+        if k == items.len() {
+            visit(items);
+            return;
+        }
+        for j in k..items.len() {
+            items.swap(k, j);
+            permute(items, k + 1, visit);
+            items.swap(k, j);
+        }
+    }
+
+    #[test]
+    fn test_boxes_count_as_before() {
+        for dims in [vec![1], vec![7], vec![2, 3], vec![3, 3], vec![2, 5], vec![2, 2, 2], vec![2, 2, 3]] {
+            let map = Polyomino::rectangle(&dims);
+            assert_eq!(map.count_foldings(), StampFolder::calculate_sequence(&dims), "{:?}", dims);
+        }
+        let folding = crate::rank::unrank(&[3, 2], 11).unwrap();
+        assert!(Polyomino::rectangle(&[3, 2]).is_valid(&folding));
+    }
+
+    #[test]
+    fn test_shapes_match_brute_force() {
+        let shapes = [
+            "###\n#..",         // L
+            "###\n.#.",         // T
+            ".#.\n###\n.#.",    // cross
+            "##.\n.##",         // S
+            "###\n#.#\n###",    // ring
+            "#.#\n###",         // U, where leaf 2 joins on only from above
+            "####\n#..#\n#..#", // arch
+        ];
+        for shape in shapes {
+            let map = Polyomino::parse(&format!("{}\n{}\n", MAP_HEADER, shape)).unwrap();
+            assert_eq!(map.count_foldings(), brute_force(&map), "{}", shape);
+        }
+        // A cross has at most one crease on each edge of the stack, so every order folds
+        let cross = Polyomino::parse("folds-map v1\n.#.\n###\n.#.\n").unwrap();
+        assert_eq!(cross.leaves()[3], vec![2, 1]);
+        assert_eq!(cross.count_foldings(), 120);
+        // Without its middle leaf the 3x3 square folds in far more ways
+        let ring = Polyomino::parse("folds-map v1\n###\n#.#\n###\n").unwrap();
+        assert_eq!(ring.count_foldings(), 8016);
+    }
+
+    #[test]
+    fn test_rejects_bad_maps() {
+        assert!(Polyomino::parse("folds-map v1\n#.#\n").is_err());
+        assert!(Polyomino::parse("folds-map v1\n#x\n").is_err());
+        assert!(Polyomino::parse("folds-map v1\n...\n").is_err());
+        assert!(Polyomino::parse("##\n##\n").is_err());
+        assert!(Polyomino::new(&[2, 2], vec![true, false, false, true]).is_err());
+        assert_eq!(Polyomino::parse("folds-map v1\n##\n#\n").unwrap().dims, vec![2, 2]);
+    }
+}
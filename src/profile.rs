@@ -0,0 +1,196 @@
+//! Shape of the search tree per level (nodes, branching, dead ends, time), for choosing split
+//! depths and checking estimators.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::cpu::{PartSearch, PartStats};
+
+/// What the search did at one level, i.e. while placing one leaf.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LevelProfile {
+    /// The leaf placed at this level.
+    pub level: usize,
+    /// Placements of the leaf.
+    pub nodes: u64,
+    /// Gap computations for the leaf.
+    pub expansions: u64,
+    /// Expansions that found no gap.
+    pub dead_ends: u64,
+    /// Positions cut by the normalization check before the leaf was expanded.
+    pub pruned: u64,
+    /// `gaps[k]` is the number of expansions that found exactly k gaps.
+    pub gaps: Vec<u64>,
+    /// Time spent computing gaps for the leaf.
+    pub time: Duration,
+}
+
+impl LevelProfile {
+    pub fn mean_gaps(&self) -> f64 {
+        if self.expansions == 0 {
+            return 0.0;
+        }
+        let total: u64 = self.gaps.iter().enumerate().map(|(k, &count)| k as u64 * count).sum();
+        total as f64 / self.expansions as f64
+    }
+
+    pub fn max_gaps(&self) -> usize {
+        self.gaps.iter().rposition(|&count| count > 0).unwrap_or(0)
+    }
+}
+
+// Per-level counters kept by a profiling StampFolder; index l - 1 is leaf l
+pub(crate) fn new_levels(n: usize) -> Vec<LevelProfile> {
+    (1..=n).map(|level| LevelProfile { level, ..LevelProfile::default() }).collect()
+}
+
+pub(crate) fn record_expansion(levels: &mut [LevelProfile], l: i32, gaps: i32, started: Instant) {
+    let level = &mut levels[(l - 1) as usize];
+    level.expansions += 1;
+    level.dead_ends += (gaps == 0) as u64;
+    if level.gaps.len() <= gaps as usize {
+        level.gaps.resize(gaps as usize + 1, 0);
+    }
+    level.gaps[gaps as usize] += 1;
+    level.time += started.elapsed();
+}
+
+/// Profile of one `res/mod` part (or the whole search, as part 0 of 1).
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeProfile {
+    pub dims: Vec<i32>,
+    pub part: usize,
+    pub total_parts: usize,
+    pub stats: PartStats,
+    pub levels: Vec<LevelProfile>,
+}
+
+impl TreeProfile {
+    pub fn compute(dims: &[i32], part: usize, total_parts: usize) -> Self {
+        let _span = tracing::debug_span!("profile", ?dims, part, total_parts).entered();
+        let mut search = PartSearch::new(dims, part, total_parts);
+        search.enable_profile();
+        search.step(u64::MAX);
+        TreeProfile {
+            dims: dims.to_vec(),
+            part,
+            total_parts,
+            stats: search.stats(),
+            levels: search.take_profile().unwrap_or_default(),
+        }
+    }
+
+    /// The shallowest depth with at least `units` subtrees below it: splitting there gives
+    /// every unit a share of the work. Stops one short of the last leaf.
+    pub fn split_depth(&self, units: usize) -> usize {
+        let last = self.levels.len().saturating_sub(1);
+        self.levels[..last]
+            .iter()
+            .find(|level| level.nodes >= units as u64)
+            .map_or(last, |level| level.level)
+    }
+
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "{:?} part {} of {}: {} foldings, {} nodes\n{:>5} {:>14} {:>14} {:>12} {:>12} {:>9} {:>8} {:>10}\n",
+            self.dims,
+            self.part,
+            self.total_parts,
+            self.stats.count,
+            self.stats.nodes,
+            "level",
+            "nodes",
+            "expansions",
+            "dead ends",
+            "pruned",
+            "mean gaps",
+            "max gaps",
+            "time ms",
+        );
+        for level in &self.levels {
+            let _ = writeln!(
+                table,
+                "{:>5} {:>14} {:>14} {:>12} {:>12} {:>9.3} {:>8} {:>10.3}",
+                level.level,
+                level.nodes,
+                level.expansions,
+                level.dead_ends,
+                level.pruned,
+                level.mean_gaps(),
+                level.max_gaps(),
+                level.time.as_secs_f64() * 1000.0,
+            );
+        }
+        table
+    }
+
+    pub fn to_json(&self) -> String {
+        let levels: Vec<String> = self
+            .levels
+            .iter()
+            .map(|level| {
+                let gaps: Vec<String> = level.gaps.iter().map(|count| count.to_string()).collect();
+                format!(
+                    "{{\"level\":{},\"nodes\":{},\"expansions\":{},\"dead_ends\":{},\"pruned\":{},\"gaps\":[{}],\"seconds\":{}}}",
+                    level.level,
+                    level.nodes,
+                    level.expansions,
+                    level.dead_ends,
+                    level.pruned,
+                    gaps.join(","),
+                    level.time.as_secs_f64(),
+                )
+            })
+            .collect();
+        let dims: Vec<String> = self.dims.iter().map(|d| d.to_string()).collect();
+        format!(
+            "{{\"dims\":[{}],\"part\":{},\"parts\":{},\"count\":{},\"nodes\":{},\"levels\":[{}]}}",
+            dims.join(","),
+            self.part,
+            self.total_parts,
+            self.stats.count,
+            self.stats.nodes,
+            levels.join(","),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::StampFolder;
+
+    #[test]
+    fn test_profile_accounts_for_the_search() {
+        let profile = TreeProfile::compute(&[3, 3], 0, 1);
+        assert_eq!(profile.stats, StampFolder::calculate_part_stats(&[3, 3], 0, 1));
+        assert_eq!(profile.levels.len(), 9);
+        assert_eq!(profile.levels.iter().map(|level| level.nodes).sum::<u64>(), profile.stats.nodes);
+
+        for level in &profile.levels {
+            assert_eq!(level.gaps.iter().sum::<u64>(), level.expansions);
+            assert_eq!(level.gaps.first().copied().unwrap_or(0), level.dead_ends);
+            // Every gap found for a leaf is tried once
+            assert_eq!(level.gaps.iter().enumerate().map(|(k, &c)| k as u64 * c).sum::<u64>(), level.nodes);
+        }
+        // The first leaf always has exactly one place to go
+        assert_eq!(profile.levels[0].nodes, 1);
+    }
+
+    #[test]
+    fn test_split_depth_and_output() {
+        let whole = TreeProfile::compute(&[3, 3], 0, 1);
+        assert_eq!(whole.split_depth(1), 1);
+        assert_eq!(whole.split_depth(1_000_000), 8);
+        let depth = whole.split_depth(20);
+        assert!(whole.levels[depth - 1].nodes >= 20);
+        assert!(whole.levels[..depth - 1].iter().all(|level| level.nodes < 20));
+
+        let profile = TreeProfile::compute(&[2, 4], 1, 3);
+        assert_eq!(profile.stats.count, StampFolder::calculate_sequence_part(&[2, 4], 1, 3));
+        assert_eq!(profile.to_table().lines().count(), 10);
+        let json = profile.to_json();
+        assert!(json.starts_with("{\"dims\":[2,4],\"part\":1,\"parts\":3,"));
+        assert_eq!(json.matches("\"level\":").count(), 8);
+    }
+}
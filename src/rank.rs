@@ -0,0 +1,154 @@
+//! A numbering of the foldings of a map by 0..count, in the order the search finds them.
+//!
+//! The search visits the stacks with leaf 1 on top depth first, trying gaps in its own order;
+//! stack s stands for n foldings, its rotations. Folding `s.rotated(r)` has index
+//! `n * (stacks before s) + r`. Ranking and unranking walk one branch of the search tree and
+//! count the subtrees beside it, which a `Ranker` keeps for later calls.
+
+use crate::folding::{Folding, SubtreeCounts};
+
+pub struct Ranker {
+    counts: SubtreeCounts,
+}
+
+impl Ranker {
+    pub fn new(dims: &[i32]) -> Self {
+        Ranker { counts: SubtreeCounts::new(dims) }
+    }
+
+    /// Number of foldings, the end of the index range.
+    pub fn total(&mut self) -> u128 {
+        self.counts.count(&[]) as u128
+    }
+
+    /// The index of `folding`, or `None` if it is not a folding of the map.
+    pub fn rank(&mut self, folding: &Folding) -> Option<u128> {
+        let n = self.counts.leaves();
+        let mut leaves = folding.order.clone();
+        leaves.sort_unstable();
+        if n == 0 || !leaves.into_iter().eq(1..=n as i32) {
+            return None;
+        }
+        let top = folding.positions()[1];
+        let stack = folding.rotated(top);
+
+        let mut index = 0;
+        let mut prefix = Vec::with_capacity(n);
+        for m in stack_gaps(&stack) {
+            let gaps = self.counts.children(&prefix);
+            let chosen = gaps.iter().position(|&gap| gap == m)?;
+            for &gap in &gaps[..chosen] {
+                prefix.push(gap);
+                index += self.counts.count(&prefix) as u128;
+                prefix.pop();
+            }
+            prefix.push(m);
+        }
+        if self.counts.count(&prefix) == 0 {
+            return None;
+        }
+        Some(index + ((n - top) % n) as u128)
+    }
+
+    /// The folding with index `index`, or `None` past the end.
+    pub fn unrank(&mut self, index: u128) -> Option<Folding> {
+        let n = self.counts.leaves();
+        if n == 0 || index >= self.total() {
+            return None;
+        }
+
+        let mut rest = index;
+        let mut prefix = Vec::with_capacity(n);
+        while prefix.len() < n {
+            for gap in self.counts.children(&prefix) {
+                prefix.push(gap);
+                let count = self.counts.count(&prefix) as u128;
+                if rest < count {
+                    break;
+                }
+                rest -= count;
+                prefix.pop();
+            }
+        }
+        Some(Folding::from_gaps(&prefix).rotated(rest as usize))
+    }
+}
+
+// The gap each leaf went into when the search built `stack`: the nearest smaller leaf above it
+fn stack_gaps(stack: &Folding) -> Vec<i32> {
+    let mut gaps = vec![0; stack.order.len()];
+    let mut above: Vec<i32> = Vec::new();
+    for &leaf in &stack.order {
+        while above.last().is_some_and(|&upper| upper > leaf) {
+            above.pop();
+        }
+        gaps[(leaf - 1) as usize] = above.last().copied().unwrap_or(0);
+        above.push(leaf);
+    }
+    gaps
+}
+
+/// The index of `folding` among the foldings of `dims`; see `Ranker` for repeated calls.
+pub fn rank(dims: &[i32], folding: &Folding) -> Option<u128> {
+    Ranker::new(dims).rank(folding)
+}
+
+/// The folding of `dims` with index `index`.
+pub fn unrank(dims: &[i32], index: u128) -> Option<Folding> {
+    Ranker::new(dims).unrank(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::StampFolder;
+
+    // Stacks with leaf 1 on top in the order the search completes them
+    fn search_order(counts: &mut SubtreeCounts, prefix: &mut Vec<i32>, stacks: &mut Vec<Folding>) {
+        if prefix.len() == counts.leaves() {
+            if counts.count(prefix) > 0 {
+                stacks.push(Folding::from_gaps(prefix));
+            }
+            return;
+        }
+        for gap in counts.children(prefix) {
+            prefix.push(gap);
+            search_order(counts, prefix, stacks);
+            prefix.pop();
+        }
+    }
+
+    #[test]
+    fn test_ranks_follow_the_search() {
+        for dims in [vec![5], vec![2, 3], vec![2, 2, 2]] {
+            let mut stacks = Vec::new();
+            search_order(&mut SubtreeCounts::new(&dims), &mut Vec::new(), &mut stacks);
+            let n = stacks[0].order.len();
+
+            let mut ranker = Ranker::new(&dims);
+            assert_eq!(ranker.total(), StampFolder::calculate_sequence(&dims) as u128);
+            assert_eq!(ranker.total(), (stacks.len() * n) as u128);
+            for (s, stack) in stacks.iter().enumerate() {
+                for r in 0..n {
+                    let index = (s * n + r) as u128;
+                    let folding = stack.rotated(r);
+                    assert_eq!(ranker.unrank(index).as_ref(), Some(&folding), "{:?} {}", dims, index);
+                    assert_eq!(ranker.rank(&folding), Some(index));
+                }
+            }
+            let total = ranker.total();
+            assert_eq!(ranker.unrank(total), None);
+        }
+    }
+
+    #[test]
+    fn test_rank_rejects_non_foldings() {
+        assert_eq!(rank(&[2, 2], &Folding::new(vec![1, 4, 2, 3])), None);
+        assert_eq!(rank(&[2, 2], &Folding::new(vec![1, 2, 3])), None);
+        assert_eq!(rank(&[2, 2], &Folding::new(vec![1, 2, 4, 4])), None);
+
+        let folding = unrank(&[3, 3], 1234).unwrap();
+        assert!(folding.is_valid(&[3, 3]));
+        assert_eq!(rank(&[3, 3], &folding), Some(1234));
+    }
+}
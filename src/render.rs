@@ -0,0 +1,328 @@
+//! Pictures of one folding of a map with at least one leaf: the unfolded map with its leaf
+//! labels and the fold of every crease, and for each axis a side view of the stack with that
+//! axis's creases drawn on the two edges, where they nest. As terminal text or as a standalone
+//! SVG document.
+//!
+//! The map is drawn with axis 1 across and axis 2 down; maps with more axes are drawn as one
+//! such slice per value of the remaining coordinates. In the side views the creases after an
+//! even coordinate are on the right edge, the others on the left.
+
+use std::fmt::Write;
+
+use crate::folding::{leaf_count, Fold, Folding};
+use crate::mv::MvPattern;
+
+const CELL: usize = 48;
+const LAYER: usize = 24;
+const MARGIN: usize = 20;
+
+// A crease in a side view: the stack rows of its two leaves and how many creases it encloses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Bracket {
+    top: usize,
+    bottom: usize,
+    depth: usize,
+}
+
+// The creases along `axis` on each edge of the stack, [right, left]
+fn brackets(dims: &[i32], folding: &Folding, axis: usize) -> [Vec<Bracket>; 2] {
+    let position = folding.positions();
+    let stride: i32 = dims[..axis].iter().product();
+    let mut edges = [Vec::new(), Vec::new()];
+    for leaf in 1..=leaf_count(dims) as i32 {
+        let x = (leaf - 1) / stride % dims[axis];
+        if x + 1 < dims[axis] {
+            let (u, v) = (position[leaf as usize], position[(leaf + stride) as usize]);
+            edges[(x % 2) as usize].push(Bracket { top: u.min(v), bottom: u.max(v), depth: 0 });
+        }
+    }
+    for edge in &mut edges {
+        // Narrow ones first, so everything a bracket encloses already has its depth
+        edge.sort_by_key(|bracket| bracket.bottom - bracket.top);
+        for i in 0..edge.len() {
+            let (top, bottom) = (edge[i].top, edge[i].bottom);
+            edge[i].depth = edge[..i]
+                .iter()
+                .filter(|inner| top < inner.top && inner.bottom < bottom)
+                .map(|inner| inner.depth + 1)
+                .max()
+                .unwrap_or(0);
+        }
+    }
+    edges
+}
+
+// The leaves of the map slice by slice, each slice as rows of axis 1
+fn slices(dims: &[i32]) -> Vec<(Vec<i32>, Vec<Vec<i32>>)> {
+    let columns = dims[0];
+    let rows = dims.get(1).copied().unwrap_or(1);
+    let slice_size = columns * rows;
+    (0..leaf_count(dims) as i32 / slice_size)
+        .map(|slice| {
+            let mut rest = slice;
+            let higher: Vec<i32> = dims
+                .iter()
+                .skip(2)
+                .map(|&p| {
+                    let x = rest % p;
+                    rest /= p;
+                    x
+                })
+                .collect();
+            let leaves = (0..rows)
+                .map(|y| (0..columns).map(|x| 1 + x + y * columns + slice * slice_size).collect())
+                .collect();
+            (higher, leaves)
+        })
+        .collect()
+}
+
+fn fold_char(fold: Option<Fold>) -> char {
+    match fold {
+        Some(Fold::Mountain) => 'M',
+        Some(Fold::Valley) => 'V',
+        None => ' ',
+    }
+}
+
+/// The map with `M`/`V` on every crease, then a side view of the stack per axis.
+pub fn render_ascii(dims: &[i32], folding: &Folding) -> String {
+    let pattern = MvPattern::of(dims, folding);
+    let width = leaf_count(dims).to_string().len();
+    let mut text = String::new();
+
+    for (higher, rows) in slices(dims) {
+        if !higher.is_empty() {
+            let coordinates: Vec<String> = higher.iter().enumerate().map(|(i, x)| format!("x{}={}", i + 3, x)).collect();
+            let _ = writeln!(text, "slice {}", coordinates.join(" "));
+        }
+        for (y, row) in rows.iter().enumerate() {
+            let mut line = String::new();
+            let mut below = String::new();
+            for (x, &leaf) in row.iter().enumerate() {
+                let _ = write!(line, "{:>width$}", leaf);
+                if y + 1 < rows.len() {
+                    let _ = write!(below, "{:>width$}", fold_char(pattern.fold(1, leaf)));
+                }
+                if x + 1 < row.len() {
+                    let _ = write!(line, " {} ", fold_char(pattern.fold(0, leaf)));
+                    below.push_str("   ");
+                }
+            }
+            let _ = writeln!(text, "{}", line.trim_end());
+            if y + 1 < rows.len() {
+                let _ = writeln!(text, "{}", below.trim_end());
+            }
+        }
+        text.push('\n');
+    }
+
+    for axis in 0..dims.len() {
+        let [right, left] = brackets(dims, folding, axis);
+        let columns = |edge: &[Bracket]| edge.iter().map(|bracket| bracket.depth + 1).max().unwrap_or(0);
+        let (left_width, right_width) = (columns(&left), columns(&right));
+        let n = folding.order.len();
+        let mut left_grid = vec![vec![' '; left_width]; n];
+        let mut right_grid = vec![vec![' '; right_width]; n];
+        for bracket in &left {
+            let column = left_width - 1 - bracket.depth;
+            draw_bracket(&mut left_grid, bracket, column, column + 1..left_width);
+        }
+        for bracket in &right {
+            draw_bracket(&mut right_grid, bracket, bracket.depth, 0..bracket.depth);
+        }
+
+        // A layer is drawn as ==k==, joined to the creases that end at it
+        let connector = |edge: &[Bracket], row: usize| match edge.iter().any(|b| b.top == row || b.bottom == row) {
+            true => "-",
+            false if edge.is_empty() => "",
+            false => " ",
+        };
+        let _ = writeln!(text, "axis {} side view", axis + 1);
+        for (row, &leaf) in folding.order.iter().enumerate() {
+            let line = format!(
+                "{}{}{:=^width$}{}{}",
+                left_grid[row].iter().collect::<String>(),
+                connector(&left, row),
+                leaf,
+                connector(&right, row),
+                right_grid[row].iter().collect::<String>(),
+                width = width + 4
+            );
+            let _ = writeln!(text, "{}", line.trim_end());
+        }
+        text.push('\n');
+    }
+    text.pop();
+    text
+}
+
+fn draw_bracket(grid: &mut [Vec<char>], bracket: &Bracket, column: usize, arm: std::ops::Range<usize>) {
+    for row in [bracket.top, bracket.bottom] {
+        grid[row][column] = '+';
+        for c in arm.clone() {
+            grid[row][c] = '-';
+        }
+    }
+    for row in &mut grid[bracket.top + 1..bracket.bottom] {
+        row[column] = '|';
+    }
+}
+
+/// The same pictures as a standalone SVG document: mountains as red dash-dot lines, valleys as
+/// blue dashed lines, creases in the side views as arcs.
+pub fn render_svg(dims: &[i32], folding: &Folding) -> String {
+    let pattern = MvPattern::of(dims, folding);
+    let n = folding.order.len();
+    let slices = slices(dims);
+    let (columns, rows) = (dims[0] as usize, dims.get(1).copied().unwrap_or(1) as usize);
+    let map_width = slices.len() * (columns * CELL + MARGIN);
+
+    let side_views: Vec<[Vec<Bracket>; 2]> = (0..dims.len()).map(|axis| brackets(dims, folding, axis)).collect();
+    let reach = |edge: &[Bracket]| edge.iter().map(|b| (b.bottom - b.top) * LAYER / 2).max().unwrap_or(0);
+    let view_widths: Vec<(usize, usize)> = side_views.iter().map(|[right, left]| (reach(left), reach(right))).collect();
+    let views_width: usize = view_widths.iter().map(|(left, right)| left + right + 2 * CELL + MARGIN).sum();
+
+    let width = MARGIN + map_width.max(views_width);
+    let views_top = 2 * MARGIN + rows * CELL + MARGIN;
+    let height = views_top + n * LAYER + 2 * MARGIN;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" font-family=\"sans-serif\" font-size=\"14\">\n",
+        width, height, width, height
+    );
+    let mountain = "stroke=\"#c0392b\" stroke-width=\"2\" stroke-dasharray=\"8 3 2 3\"";
+    let valley = "stroke=\"#2471a3\" stroke-width=\"2\" stroke-dasharray=\"6 4\"";
+    let style = |fold: Option<Fold>| match fold {
+        Some(Fold::Mountain) => mountain,
+        _ => valley,
+    };
+
+    for (s, (higher, leaves)) in slices.iter().enumerate() {
+        let left = MARGIN + s * (columns * CELL + MARGIN);
+        let top = 2 * MARGIN;
+        if !higher.is_empty() {
+            let coordinates: Vec<String> = higher.iter().enumerate().map(|(i, x)| format!("x{}={}", i + 3, x)).collect();
+            let _ = writeln!(svg, "<text x=\"{}\" y=\"{}\">{}</text>", left, top - 6, coordinates.join(" "));
+        }
+        for (y, row) in leaves.iter().enumerate() {
+            for (x, &leaf) in row.iter().enumerate() {
+                let (cx, cy) = (left + x * CELL, top + y * CELL);
+                let _ = writeln!(
+                    svg,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#fdfefe\" stroke=\"#7f8c8d\"/>",
+                    cx, cy, CELL, CELL
+                );
+                let _ = writeln!(
+                    svg,
+                    "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" dominant-baseline=\"central\">{}</text>",
+                    cx + CELL / 2,
+                    cy + CELL / 2,
+                    leaf
+                );
+                if x + 1 < row.len() {
+                    let _ = writeln!(
+                        svg,
+                        "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" {}/>",
+                        cx + CELL,
+                        cy,
+                        cx + CELL,
+                        cy + CELL,
+                        style(pattern.fold(0, leaf))
+                    );
+                }
+                if y + 1 < leaves.len() {
+                    let _ = writeln!(
+                        svg,
+                        "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" {}/>",
+                        cx,
+                        cy + CELL,
+                        cx + CELL,
+                        cy + CELL,
+                        style(pattern.fold(1, leaf))
+                    );
+                }
+            }
+        }
+    }
+
+    let mut left = MARGIN;
+    for (axis, ([right_edge, left_edge], &(left_reach, right_reach))) in side_views.iter().zip(&view_widths).enumerate() {
+        let (x0, x1) = (left + left_reach, left + left_reach + 2 * CELL);
+        let _ = writeln!(svg, "<text x=\"{}\" y=\"{}\">axis {}</text>", x0, views_top - 6, axis + 1);
+        for (row, &leaf) in folding.order.iter().enumerate() {
+            let y = views_top + row * LAYER + LAYER / 2;
+            let _ = writeln!(svg, "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"#2c3e50\" stroke-width=\"2\"/>", x0, y, x1, y);
+            let _ = writeln!(svg, "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>", (x0 + x1) / 2, y - 3, leaf);
+        }
+        for (edge, x, sweep) in [(left_edge, x0, 0), (right_edge, x1, 1)] {
+            for bracket in edge {
+                let (y0, y1) = (views_top + bracket.top * LAYER + LAYER / 2, views_top + bracket.bottom * LAYER + LAYER / 2);
+                let radius = (y1 - y0) / 2;
+                let _ = writeln!(
+                    svg,
+                    "<path d=\"M {} {} A {} {} 0 0 {} {} {}\" fill=\"none\" stroke=\"#2c3e50\" stroke-width=\"2\"/>",
+                    x, y0, radius, radius, sweep, x, y1
+                );
+            }
+        }
+        left += left_reach + right_reach + 2 * CELL + MARGIN;
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ascii_map_and_side_views() {
+        let folding: Folding = "2 1 3 6 4 5".parse().unwrap();
+        let text = render_ascii(&[3, 2], &folding);
+        let expected = "\
+1 V 2 V 3
+M   V   M
+4 V 5 V 6
+
+axis 1 side view
++-==2==-+
+| ==1==-+
++-==3==
++-==6==
+| ==4==-+
++-==5==-+
+
+axis 2 side view
+==2==---+
+==1==--+|
+==3==-+||
+==6==-+||
+==4==--+|
+==5==---+
+";
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn test_brackets_nest() {
+        let folding = crate::rank::unrank(&[3, 3], 500).unwrap();
+        for axis in 0..2 {
+            for edge in brackets(&[3, 3], &folding, axis) {
+                for a in &edge {
+                    for b in &edge {
+                        if a.top < b.top && b.bottom < a.bottom {
+                            assert!(a.depth > b.depth);
+                        }
+                    }
+                }
+            }
+        }
+
+        let svg = render_svg(&[2, 2, 2], &crate::rank::unrank(&[2, 2, 2], 40).unwrap());
+        assert!(svg.starts_with("<svg ") && svg.ends_with("</svg>\n"));
+        // Labels: 8 on the map, 8 per side view, plus the slice and view titles
+        assert_eq!(svg.matches("<text").count(), 8 + 3 * 8 + 2 + 3);
+        assert_eq!(svg.matches("<path").count(), 3 * 4);
+    }
+}
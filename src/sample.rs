@@ -0,0 +1,76 @@
+//! Foldings drawn uniformly at random, for looking at typical stacks and as test inputs.
+//!
+//! Each draw walks down the search tree choosing every gap with probability proportional to the
+//! number of foldings below it, then rotates the stack it reaches by a uniform amount, so every
+//! folding is equally likely. Subtree counts are kept between draws; the first draw costs about
+//! as much as counting the map.
+
+use crate::folding::{Folding, SubtreeCounts};
+use crate::rng::SplitMix64;
+
+/// `k` foldings of `dims`, independent and uniform over all foldings, reproducible from `seed`.
+pub fn sample_foldings(dims: &[i32], k: usize, seed: u64) -> Vec<Folding> {
+    let _span = tracing::debug_span!("sample", ?dims, k, seed).entered();
+    if dims.contains(&0) {
+        return vec![Folding::new(Vec::new()); k];
+    }
+
+    let mut rng = SplitMix64::new(seed);
+    let mut counts = SubtreeCounts::new(dims);
+    let n = counts.leaves();
+    (0..k)
+        .map(|_| {
+            let mut prefix = Vec::with_capacity(n);
+            while prefix.len() < n {
+                let gaps = counts.children(&prefix);
+                let weights: Vec<i64> = gaps
+                    .iter()
+                    .map(|&m| {
+                        prefix.push(m);
+                        let count = counts.count(&prefix);
+                        prefix.pop();
+                        count
+                    })
+                    .collect();
+
+                let mut pick = rng.below(weights.iter().sum::<i64>() as u64) as i64;
+                let chosen = weights
+                    .iter()
+                    .position(|&weight| {
+                        pick -= weight;
+                        pick < 0
+                    })
+                    .unwrap();
+                prefix.push(gaps[chosen]);
+            }
+            Folding::from_gaps(&prefix).rotated(rng.below(n as u64) as usize)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_samples_are_valid_and_reproducible() {
+        for dims in [vec![6], vec![3, 3], vec![2, 2, 2]] {
+            let samples = sample_foldings(&dims, 50, 11);
+            assert!(samples.iter().all(|folding| folding.is_valid(&dims)), "{:?}", dims);
+            assert_eq!(samples, sample_foldings(&dims, 50, 11));
+        }
+        assert_ne!(sample_foldings(&[3, 3], 5, 1), sample_foldings(&[3, 3], 5, 2));
+    }
+
+    #[test]
+    fn test_samples_are_uniform() {
+        // 2x2 has 8 foldings; 4000 draws put each near 500
+        let mut seen: HashMap<Folding, usize> = HashMap::new();
+        for folding in sample_foldings(&[2, 2], 4000, 3) {
+            *seen.entry(folding).or_default() += 1;
+        }
+        assert_eq!(seen.len(), 8);
+        assert!(seen.values().all(|&times| (400..600).contains(&times)), "{:?}", seen);
+    }
+}
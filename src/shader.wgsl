@@ -1,194 +1,181 @@
-// Binding for input/output buffers and constants
+// One invocation searches one work unit: a residue of the res/mod split, or the subtree
+// below a prefix of gap choices. The d table is precomputed on the host.
+//
+// The search runs in passes of at most `max_steps` iterations, keeping its state in `state`
+// between dispatches, so no single dispatch runs long (and software rasterizers that cap
+// loop iterations per invocation still finish).
 struct Params {
     @align(4) dim: u32,
     @align(4) n: u32,
-    @align(4) mod_val: u32,
-    @align(4) res: i32,
+    @align(4) unit_count: u32,
+    @align(4) unit_stride: u32,   // 3 + n: mod_val, res, prefix_len, prefix...
+    @align(4) state_stride: u32,  // STATE_GAP + gap entries per unit
+    @align(4) table_stride: u32,  // n + 1
+    @align(4) max_steps: u32,
+    @align(4) first_pass: u32,
 }
 
 @group(0) @binding(0) var<uniform> params: Params;
-@group(0) @binding(1) var<storage, read> p_array: array<i32>;
-@group(0) @binding(2) var<storage, read_write> count_buffer: array<atomic<i32>>;
+@group(0) @binding(1) var<storage, read> d_table: array<i32>;
+@group(0) @binding(2) var<storage, read> units: array<i32>;
+@group(0) @binding(3) var<storage, read_write> state: array<i32>;
+// [0]: units still running after this pass, [1]: unused, then (low, high) count words per unit
+@group(0) @binding(4) var<storage, read_write> results: array<atomic<u32>>;
 
 // Constants
 const MAX_N: u32 = 64u;
-const MAX_N_SQ: u32 = 4096u;    // MAX_N * MAX_N
-const MAX_N_CUBE: u32 = 262144u; // MAX_N * MAX_N * MAX_N
-
-// Shared arrays (stored in workgroup memory for better performance)
-var<workgroup> big_p: array<i32, MAX_N>;
-var<workgroup> c: array<array<i32, MAX_N>, MAX_N>;
-var<workgroup> d: array<i32, MAX_N_CUBE>;
-var<workgroup> gap: array<i32, MAX_N_SQ>;
-var<workgroup> count_array: array<atomic<i32>, MAX_N>;
-var<workgroup> gapter: array<i32, MAX_N>;
-var<workgroup> a: array<i32, MAX_N>;
-var<workgroup> b: array<i32, MAX_N>;
-
-fn get_d_index(i: u32, l: u32, m: u32) -> u32 {
-    return i * MAX_N_SQ + l * MAX_N + m;
-}
-
-fn calculate_big_p() {
-    big_p[0] = 1;
-    for(var i: u32 = 1u; i <= params.dim; i = i + 1u) {
-        big_p[i] = big_p[i - 1] * p_array[i - 1];
-    }
-}
 
-fn calculate_c(i: u32, m: i32) -> i32 {
-    let big_p_im1 = big_p[i - 1];
-    let big_p_i = big_p[i];
-    let p_im1 = p_array[i - 1];
-    return (m - 1) / big_p_im1 - ((m - 1) / big_p_i) * p_im1 + 1;
-}
-
-fn calculate_d(i: u32, l: i32, m: i32) -> i32 {
-    let l_idx = u32(l);
-    let m_idx = u32(m);
-    let delta = c[i][l_idx] - c[i][m_idx];
+// Layout of one unit's state
+const STATE_L: u32 = 0u;
+const STATE_G: u32 = 1u;
+const STATE_A: u32 = 2u;
+const STATE_B: u32 = 66u;
+const STATE_GAPTER: u32 = 130u;
+const STATE_COUNT_ARRAY: u32 = 194u;
+const STATE_GAP: u32 = 258u;
 
-    if ((delta & 1) == 0) {
-        if (c[i][m_idx] == 1) {
-            return m;
-        } else {
-            return m - big_p[i - 1];
-        }
-    } else {
-        if (c[i][m_idx] == p_array[i - 1] || m + big_p[i - 1] > l) {
-            return m;
-        } else {
-            return m + big_p[i - 1];
-        }
-    }
-}
-
-fn precalculate_arrays() {
-    // Initialize arrays first
-    for(var i: u32 = 0u; i < MAX_N; i = i + 1u) {
-        big_p[i] = 0;
-        gapter[i] = 0;
-        a[i] = i32(i);
-        b[i] = i32(i);
-        for(var j: u32 = 0u; j < MAX_N; j = j + 1u) {
-            c[i][j] = 0;
-        }
-    }
-
-    calculate_big_p();
-
-    // Calculate c array
-    for(var i: u32 = 1u; i <= params.dim; i = i + 1u) {
-        for(var m: u32 = 1u; m <= params.n; m = m + 1u) {
-            c[i][m] = calculate_c(i, i32(m));
-        }
-    }
+var<private> base: u32;
 
-    // Calculate d array
-    for(var i: u32 = 1u; i <= params.dim; i = i + 1u) {
-        for(var l: u32 = 1u; l <= params.n; l = l + 1u) {
-            for(var m: u32 = 1u; m <= l; m = m + 1u) {
-                let idx = get_d_index(i, l, m);
-                d[idx] = calculate_d(i, i32(l), i32(m));
-            }
-        }
-    }
+fn get_d(i: u32, l: i32, m: i32) -> i32 {
+    return d_table[(i * params.table_stride + u32(l)) * params.table_stride + u32(m)];
 }
 
-fn process_gaps(l: i32, g: ptr<function, i32>, gg: ptr<function, i32>, dd: ptr<function, i32>) {
+fn a(i: i32) -> i32 { return state[base + STATE_A + u32(i)]; }
+fn b(i: i32) -> i32 { return state[base + STATE_B + u32(i)]; }
+fn gapter(i: i32) -> i32 { return state[base + STATE_GAPTER + u32(i)]; }
+fn gap(i: i32) -> i32 { return state[base + STATE_GAP + u32(i)]; }
+fn set_a(i: i32, value: i32) { state[base + STATE_A + u32(i)] = value; }
+fn set_b(i: i32, value: i32) { state[base + STATE_B + u32(i)] = value; }
+fn set_gapter(i: i32, value: i32) { state[base + STATE_GAPTER + u32(i)] = value; }
+fn set_gap(i: i32, value: i32) { state[base + STATE_GAP + u32(i)] = value; }
+
+fn process_gaps(l: i32, g: ptr<function, i32>, gg: ptr<function, i32>, mod_val: i32, res: i32) {
+    var dd: i32 = 0;
     for(var i: u32 = 1u; i <= params.dim; i = i + 1u) {
-        let l_idx = u32(l);
-        let d_idx = get_d_index(i, l_idx, l_idx);
-
-        if (d[d_idx] == l) {
-            *dd = *dd + 1;
+        if (get_d(i, l, l) == l) {
+            dd = dd + 1;
             continue;
         }
 
-        var m = d[d_idx];
+        var m = get_d(i, l, l);
         while (m != l) {
-            if (params.mod_val == 0u || l != i32(params.mod_val) || m % i32(params.mod_val) == params.res) {
-                gap[u32(*gg)] = m;
-                atomicAdd(&count_array[u32(m)], 1);
-                if (atomicLoad(&count_array[u32(m)]) == 1) {
+            if (mod_val == 0 || l != mod_val || m % mod_val == res) {
+                set_gap(*gg, m);
+                let counter = base + STATE_COUNT_ARRAY + u32(m);
+                state[counter] = state[counter] + 1;
+                if (state[counter] == 1) {
                     *gg = *gg + 1;
                 }
             }
-            let new_idx = get_d_index(i, l_idx, u32(b[u32(m)]));
-            m = d[new_idx];
+            m = get_d(i, l, b(m));
         }
     }
 
-    if (*dd == i32(params.dim)) {
+    if (dd == i32(params.dim)) {
         for(var m: i32 = 0; m < l; m = m + 1) {
-            gap[u32(*gg)] = m;
+            set_gap(*gg, m);
             *gg = *gg + 1;
         }
     }
 
     let g_start = *g;
     for(var j: i32 = g_start; j < *gg; j = j + 1) {
-        let gap_j = gap[u32(j)];
-        gap[u32(*g)] = gap_j;
-        if (atomicLoad(&count_array[u32(gap_j)]) == (i32(params.dim) - *dd)) {
+        let gap_j = gap(j);
+        set_gap(*g, gap_j);
+        let counter = base + STATE_COUNT_ARRAY + u32(gap_j);
+        if (state[counter] == (i32(params.dim) - dd)) {
             *g = *g + 1;
         }
-        atomicStore(&count_array[u32(gap_j)], 0);
+        state[counter] = 0;
     }
 }
 
 @compute @workgroup_size(64)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    let idx = global_id.x;
-    if (idx >= params.n) {
+    let unit = global_id.x;
+    if (unit >= params.unit_count) {
         return;
     }
 
-    // Initialize workgroup memory
-    if (idx == 0u) {
-        precalculate_arrays();
-    }
-
-    workgroupBarrier();
+    base = unit * params.state_stride;
+    let unit_base = unit * params.unit_stride;
+    let mod_val = units[unit_base];
+    let res = units[unit_base + 1u];
+    let prefix_len = units[unit_base + 2u];
 
     var g: i32 = 0;
-    var l: i32 = 1;
-    var local_count: i32 = 0;
+    var l: i32 = 0;
+    if (params.first_pass != 0u) {
+        for(var i: u32 = 0u; i < STATE_GAP; i = i + 1u) {
+            state[base + i] = 0;
+        }
 
-    while (l > 0) {
-        if (l <= 1 || b[0] == 1) {
-            if (l > i32(params.n)) {
-                local_count += i32(params.n);
+        // Replay the prefix: leaf k goes in after leaf prefix[k - 1]
+        for(var k: i32 = 1; k <= prefix_len; k = k + 1) {
+            let m = units[unit_base + 2u + u32(k)];
+            let b_m = b(m);
+            set_a(k, m);
+            set_b(k, b_m);
+            set_b(m, k);
+            set_a(b_m, k);
+        }
+        l = prefix_len + 1;
+    } else {
+        l = state[base + STATE_L];
+        g = state[base + STATE_G];
+    }
+
+    let n = i32(params.n);
+    var local_count: u32 = 0u;
+    var steps: u32 = 0u;
+
+    while (l > prefix_len && steps < params.max_steps) {
+        steps = steps + 1u;
+        if (l <= 1 || b(0) == 1) {
+            if (l > n) {
+                local_count = local_count + u32(n);
             } else {
-                var dd: i32 = 0;
-                var gg: i32 = gapter[u32(l - 1)];
+                var gg: i32 = gapter(l - 1);
                 g = gg;
-                process_gaps(l, &g, &gg, &dd);
+                process_gaps(l, &g, &gg, mod_val, res);
             }
         }
 
-        while (l > 0 && g == gapter[u32(l - 1)]) {
+        while (l > prefix_len && g == gapter(l - 1)) {
             l = l - 1;
-            if (l > 0) {
-                let a_l = a[u32(l)];
-                let b_l = b[u32(l)];
-                b[u32(a_l)] = b_l;
-                a[u32(b_l)] = a_l;
+            if (l > prefix_len) {
+                let a_l = a(l);
+                let b_l = b(l);
+                set_b(a_l, b_l);
+                set_a(b_l, a_l);
             }
         }
 
-        if (l > 0) {
+        if (l > prefix_len) {
             g = g - 1;
-            let gap_g = gap[u32(g)];
-            a[u32(l)] = gap_g;
-            let b_gap = b[u32(gap_g)];
-            b[u32(l)] = b_gap;
-            b[u32(gap_g)] = l;
-            a[u32(b_gap)] = l;
-            gapter[u32(l)] = g;
+            let gap_g = gap(g);
+            set_a(l, gap_g);
+            let b_gap = b(gap_g);
+            set_b(l, b_gap);
+            set_b(gap_g, l);
+            set_a(b_gap, l);
+            set_gapter(l, g);
             l = l + 1;
         }
     }
 
-    atomicAdd(&count_buffer[0], local_count);
-}
\ No newline at end of file
+    state[base + STATE_L] = l;
+    state[base + STATE_G] = g;
+
+    // Only this invocation touches its count words
+    let low = 2u + 2u * unit;
+    let previous = atomicLoad(&results[low]);
+    atomicStore(&results[low], previous + local_count);
+    if (previous + local_count < previous) {
+        atomicAdd(&results[low + 1u], 1u);
+    }
+
+    if (l > prefix_len) {
+        atomicAdd(&results[0], 1u);
+    }
+}
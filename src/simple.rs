@@ -0,0 +1,257 @@
+//! Simple folds: folding the whole current sheet along one full crease line at a time, all
+//! layers together. A pattern is simple-foldable if some sequence of simple folds folds every
+//! crease as assigned; a folding is reachable if some sequence ends in its stack.
+//!
+//! The folded sheet is a box of cells, each a stack of leaves (top first) with the way each
+//! faces. A fold at line `at` of an axis moves the cells past the line over the top or under the
+//! bottom of the rest, reversing and turning over their stacks. Which side moves does not
+//! matter: the other choice gives the same sheet turned over.
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+
+use crate::folding::{leaf_count, Fold, Folding};
+use crate::mv::MvPattern;
+
+/// One simple fold, in the coordinates of the sheet as folded so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SimpleFold {
+    pub axis: usize,
+    /// The line between cells `at` and `at + 1` along the axis.
+    pub at: usize,
+    /// Whether the cells past the line go over the top (or under the bottom).
+    pub over: bool,
+}
+
+impl fmt::Display for SimpleFold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "axis {} at {} {}", self.axis + 1, self.at, if self.over { "over" } else { "under" })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Sheet {
+    shape: Vec<usize>,
+    // Cells with the first axis fastest; stacks top first as (leaf, front faces up)
+    cells: Vec<Vec<(i32, bool)>>,
+}
+
+impl Sheet {
+    fn unfolded(dims: &[i32]) -> Self {
+        let cells = (1..=leaf_count(dims) as i32).map(|leaf| vec![(leaf, true)]).collect();
+        Sheet { shape: dims.iter().map(|&p| p as usize).collect(), cells }
+    }
+
+    fn is_folded(&self) -> bool {
+        self.cells.len() == 1
+    }
+
+    // The flat creases on a line, as (leaf before the line, its neighbour after, facing up)
+    fn creases_on(&self, dims: &[i32], axis: usize, at: usize) -> Vec<(i32, i32, bool)> {
+        let stride: i32 = dims[..axis].iter().product();
+        let mut cell_of = vec![(0, true); leaf_count(dims) + 1];
+        for (cell, stack) in self.cells.iter().enumerate() {
+            for &(leaf, up) in stack {
+                cell_of[leaf as usize] = (cell, up);
+            }
+        }
+
+        let cell_stride: usize = self.shape[..axis].iter().product();
+        let column = |cell: usize| cell / cell_stride % self.shape[axis];
+        let mut creases = Vec::new();
+        for u in 1..=leaf_count(dims) as i32 {
+            if (u - 1) / stride % dims[axis] + 1 == dims[axis] {
+                continue;
+            }
+            let v = u + stride;
+            let ((cu, up), (cv, _)) = (cell_of[u as usize], cell_of[v as usize]);
+            let (first, second) = (cu.min(cv), cu.max(cv));
+            if column(first) == at && column(second) == at + 1 && second - first == cell_stride {
+                creases.push((u, v, up));
+            }
+        }
+        creases
+    }
+
+    fn fold(&self, axis: usize, at: usize, over: bool) -> Sheet {
+        let q = self.shape[axis];
+        // Cell k > at lands on 2 at + 1 - k; shift so the lowest position is 0
+        let offset = (q as isize - 2 * at as isize - 2).max(0) as usize;
+        let mut shape = self.shape.clone();
+        shape[axis] = (at + 1).max(q - at - 1);
+        let size: usize = shape.iter().product();
+        let mut cells = vec![Vec::new(); size];
+
+        let old_stride: usize = self.shape[..axis].iter().product();
+        let new_stride: usize = shape[..axis].iter().product();
+        let mut moved = vec![Vec::new(); size];
+        for (cell, stack) in self.cells.iter().enumerate() {
+            let k = cell / old_stride % q;
+            let rest = cell - k * old_stride;
+            let (low, high) = (rest % old_stride, rest / (old_stride * q));
+            let target_k = if k <= at { k + offset } else { 2 * at + 1 + offset - k };
+            let target = low + target_k * new_stride + high * new_stride * shape[axis];
+            if k <= at {
+                cells[target] = stack.clone();
+            } else {
+                moved[target] = stack.iter().rev().map(|&(leaf, up)| (leaf, !up)).collect();
+            }
+        }
+        for (cell, flap) in cells.iter_mut().zip(moved) {
+            if over {
+                *cell = flap.into_iter().chain(cell.drain(..)).collect();
+            } else {
+                cell.extend(flap);
+            }
+        }
+        Sheet { shape, cells }
+    }
+
+    // The folded stack, seen from the side leaf 1's front faces
+    fn folding(&self) -> Folding {
+        let stack = &self.cells[0];
+        let mut order: Vec<i32> = stack.iter().map(|&(leaf, _)| leaf).collect();
+        if stack.iter().any(|&(leaf, up)| leaf == 1 && !up) {
+            order.reverse();
+        }
+        Folding::new(order)
+    }
+
+    // Folds allowed by `pattern`, or every fold when there is none
+    fn moves(&self, dims: &[i32], pattern: Option<&MvPattern>) -> Vec<SimpleFold> {
+        let mut moves = Vec::new();
+        for axis in 0..self.shape.len() {
+            for at in 0..self.shape[axis].saturating_sub(1) {
+                let creases = self.creases_on(dims, axis, at);
+                for over in [true, false] {
+                    // Going over brings the upward faces together: a valley for leaves facing up
+                    let fits = |&(u, _, up): &(i32, i32, bool)| {
+                        let fold = if over == up { Fold::Valley } else { Fold::Mountain };
+                        pattern.and_then(|pattern| pattern.fold(axis, u)).is_none_or(|wanted| wanted == fold)
+                    };
+                    if creases.iter().all(fits) {
+                        moves.push(SimpleFold { axis, at, over });
+                    }
+                }
+            }
+        }
+        moves
+    }
+}
+
+/// A sequence of simple folds that folds every crease of `pattern` as assigned (free creases
+/// either way), or `None` if there is none.
+pub fn simple_foldable(pattern: &MvPattern) -> Option<Vec<SimpleFold>> {
+    let dims = &pattern.dims;
+    let _span = tracing::debug_span!("simple foldable", ?dims).entered();
+    let mut failed = HashSet::new();
+    let mut folds = Vec::new();
+    search(dims, pattern, Sheet::unfolded(dims), &mut folds, &mut failed).then_some(folds)
+}
+
+fn search(dims: &[i32], pattern: &MvPattern, sheet: Sheet, folds: &mut Vec<SimpleFold>, failed: &mut HashSet<Sheet>) -> bool {
+    if sheet.is_folded() {
+        return true;
+    }
+    if failed.contains(&sheet) {
+        return false;
+    }
+    for fold in sheet.moves(dims, Some(pattern)) {
+        folds.push(fold);
+        if search(dims, pattern, sheet.fold(fold.axis, fold.at, fold.over), folds, failed) {
+            return true;
+        }
+        folds.pop();
+    }
+    failed.insert(sheet);
+    false
+}
+
+/// The folding a sequence of simple folds ends in, or `None` if the sequence does not apply or
+/// leaves the map partly unfolded.
+pub fn apply_simple_folds(dims: &[i32], folds: &[SimpleFold]) -> Option<Folding> {
+    let mut sheet = Sheet::unfolded(dims);
+    for fold in folds {
+        if fold.axis >= sheet.shape.len() || fold.at + 1 >= sheet.shape[fold.axis] {
+            return None;
+        }
+        sheet = sheet.fold(fold.axis, fold.at, fold.over);
+    }
+    sheet.is_folded().then(|| sheet.folding())
+}
+
+/// Every folding of `dims` that some sequence of simple folds reaches, sorted.
+pub fn simple_foldings(dims: &[i32]) -> Vec<Folding> {
+    let _span = tracing::debug_span!("simple foldings", ?dims).entered();
+    let start = Sheet::unfolded(dims);
+    let mut seen = HashSet::from([start.clone()]);
+    let mut pending = VecDeque::from([start]);
+    let mut reached = HashSet::new();
+    while let Some(sheet) = pending.pop_front() {
+        if sheet.is_folded() {
+            reached.insert(sheet.folding());
+            continue;
+        }
+        for fold in sheet.moves(dims, None) {
+            let next = sheet.fold(fold.axis, fold.at, fold.over);
+            if seen.insert(next.clone()) {
+                pending.push_back(next);
+            }
+        }
+    }
+    let mut foldings: Vec<Folding> = reached.into_iter().collect();
+    foldings.sort();
+    foldings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enumerate::for_each_folding;
+
+    #[test]
+    fn test_reachable_foldings_are_foldings() {
+        for dims in [vec![4], vec![2, 2], vec![2, 3], vec![3, 3], vec![2, 2, 2]] {
+            let reached = simple_foldings(&dims);
+            assert!(!reached.is_empty());
+            assert!(reached.iter().all(|folding| folding.is_valid(&dims)), "{:?}", dims);
+
+            // Each reached folding's pattern folds simply, and back into a folding with that pattern
+            for folding in reached.iter().step_by(7) {
+                let pattern = MvPattern::of(&dims, folding);
+                let folds = simple_foldable(&pattern).expect("reached foldings fold simply");
+                let result = apply_simple_folds(&dims, &folds).unwrap();
+                assert!(result.is_valid(&dims) && pattern.accepts(&result));
+            }
+        }
+        // Two folds in either order, each over or under: all 8 foldings of 2x2, but not all of 2x3
+        assert_eq!(simple_foldings(&[2, 2]).len(), 8);
+        assert_eq!(simple_foldings(&[5]).len(), 50);
+        assert_eq!(simple_foldings(&[2, 3]).len(), 40);
+    }
+
+    #[test]
+    fn test_patterns_that_do_not_fold_simply() {
+        // In a strip every flat-foldable pattern folds by simple folds
+        let dims = [5];
+        for_each_folding(&dims, |folding| assert!(simple_foldable(&MvPattern::of(&dims, folding)).is_some()));
+
+        // 2x4 is the smallest map with flat-foldable patterns that need more than simple folds
+        let mut patterns = HashSet::new();
+        for_each_folding(&[2, 3], |folding| {
+            patterns.insert(MvPattern::of(&[2, 3], folding));
+        });
+        assert!(patterns.iter().all(|pattern| simple_foldable(pattern).is_some()));
+        patterns.clear();
+        for_each_folding(&[2, 4], |folding| {
+            patterns.insert(MvPattern::of(&[2, 4], folding));
+        });
+        assert_eq!(patterns.iter().filter(|pattern| simple_foldable(pattern).is_none()).count(), 8);
+
+        let folds = [SimpleFold { axis: 0, at: 0, over: true }, SimpleFold { axis: 1, at: 0, over: false }];
+        assert_eq!(apply_simple_folds(&[2, 2], &folds[..1]), None);
+        assert_eq!(apply_simple_folds(&[2, 2], &[folds[1], folds[1]]), None);
+        assert!(apply_simple_folds(&[2, 2], &folds).unwrap().is_valid(&[2, 2]));
+        assert_eq!(folds[0].to_string(), "axis 1 at 0 over");
+    }
+}
@@ -0,0 +1,151 @@
+//! Foldings counted by statistics read off each folding, as the coefficients of a generating
+//! polynomial: mountain and valley creases per axis, the place of leaf 1 in the stack and how
+//! far the top leaf lies from leaf 1 on the map. Built on `enumerate`, so it costs a full
+//! enumeration; the coefficients add up to the count.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::enumerate::for_each_folding;
+use crate::folding::{coordinates, Fold, Folding};
+
+pub const POLYNOMIAL_HEADER: &str = "folds-polynomial v1";
+
+/// The exponents of one folding's term.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FoldingStats {
+    /// Mountain creases along each axis.
+    pub mountains: Vec<u32>,
+    /// Valley creases along each axis.
+    pub valleys: Vec<u32>,
+    /// Position of leaf 1 from the top of the stack, from 0.
+    pub leaf_one: u32,
+    /// Steps on the map from leaf 1 to the leaf on top.
+    pub top_depth: u32,
+}
+
+impl FoldingStats {
+    pub fn of(dims: &[i32], folding: &Folding) -> Self {
+        let mut mountains = vec![0; dims.len()];
+        let mut valleys = vec![0; dims.len()];
+        for crease in folding.creases(dims) {
+            match crease.fold {
+                Fold::Mountain => mountains[crease.axis] += 1,
+                Fold::Valley => valleys[crease.axis] += 1,
+            }
+        }
+        let leaf_one = folding.order.iter().position(|&leaf| leaf == 1).unwrap_or(0) as u32;
+        let top_depth = folding.order.first().map_or(0, |&top| coordinates(dims, top).iter().sum::<i32>() as u32);
+        FoldingStats { mountains, valleys, leaf_one, top_depth }
+    }
+}
+
+/// Number of foldings for each value of the statistics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatsPolynomial {
+    pub dims: Vec<i32>,
+    pub terms: BTreeMap<FoldingStats, u64>,
+}
+
+impl StatsPolynomial {
+    pub fn compute(dims: &[i32]) -> Self {
+        let mut terms = BTreeMap::new();
+        for_each_folding(dims, |folding| *terms.entry(FoldingStats::of(dims, folding)).or_insert(0) += 1);
+        StatsPolynomial { dims: dims.to_vec(), terms }
+    }
+
+    /// The sum of the coefficients: the number of foldings.
+    pub fn total(&self) -> u64 {
+        self.terms.values().sum()
+    }
+
+    /// Coefficients summed over everything but one statistic, picked out by `key`.
+    pub fn marginal(&self, key: impl Fn(&FoldingStats) -> u32) -> BTreeMap<u32, u64> {
+        let mut marginal = BTreeMap::new();
+        for (stats, &count) in &self.terms {
+            *marginal.entry(key(stats)).or_insert(0) += count;
+        }
+        marginal
+    }
+
+    /// One `<coefficient> <exponents...>` line per term, the exponents in the order of the
+    /// `variables` line: `m<axis>` and `v<axis>` for mountains and valleys, `p` for the place of
+    /// leaf 1 and `t` for the depth of the top leaf.
+    pub fn to_text(&self) -> String {
+        let axes = 1..=self.dims.len();
+        let variables: Vec<String> = axes
+            .clone()
+            .map(|axis| format!("m{}", axis))
+            .chain(axes.map(|axis| format!("v{}", axis)))
+            .chain(["p".to_string(), "t".to_string()])
+            .collect();
+        let dims: Vec<String> = self.dims.iter().map(|d| d.to_string()).collect();
+        let mut text = format!(
+            "{}\ndims {}\nvariables {}\nterms {}\n",
+            POLYNOMIAL_HEADER,
+            dims.join(" "),
+            variables.join(" "),
+            self.terms.len()
+        );
+        for (stats, count) in &self.terms {
+            let exponents: Vec<String> = stats
+                .mountains
+                .iter()
+                .chain(&stats.valleys)
+                .chain([&stats.leaf_one, &stats.top_depth])
+                .map(|e| e.to_string())
+                .collect();
+            let _ = writeln!(text, "{} {}", count, exponents.join(" "));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::StampFolder;
+
+    #[test]
+    fn test_coefficients_sum_to_the_count() {
+        for dims in [vec![5], vec![2, 3], vec![3, 3], vec![2, 2, 2]] {
+            let polynomial = StatsPolynomial::compute(&dims);
+            let n = dims.iter().product::<i32>();
+            assert_eq!(polynomial.total() as i64, StampFolder::calculate_sequence(&dims), "{:?}", dims);
+
+            for stats in polynomial.terms.keys() {
+                for (axis, &p) in dims.iter().enumerate() {
+                    assert_eq!((stats.mountains[axis] + stats.valleys[axis]) as i32, n / p * (p - 1));
+                }
+            }
+            // Rotations put leaf 1 everywhere equally often
+            let places = polynomial.marginal(|stats| stats.leaf_one);
+            assert_eq!(places.len(), n as usize);
+            assert!(places.values().all(|&count| count * n as u64 == polynomial.total()));
+            // Turning the stack over swaps mountains and valleys
+            for axis in 0..dims.len() {
+                assert_eq!(
+                    polynomial.marginal(|stats| stats.mountains[axis]),
+                    polynomial.marginal(|stats| stats.valleys[axis])
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_strip_of_two() {
+        // Leaf 2 folded behind leaf 1 is a mountain, folded in front a valley
+        let polynomial = StatsPolynomial::compute(&[2]);
+        let terms: Vec<(&FoldingStats, &u64)> = polynomial.terms.iter().collect();
+        assert_eq!(terms.len(), 2);
+        assert_eq!(
+            FoldingStats::of(&[2], &Folding::new(vec![1, 2])),
+            FoldingStats { mountains: vec![1], valleys: vec![0], leaf_one: 0, top_depth: 0 }
+        );
+        assert_eq!(
+            FoldingStats::of(&[2], &Folding::new(vec![2, 1])),
+            FoldingStats { mountains: vec![0], valleys: vec![1], leaf_one: 1, top_depth: 1 }
+        );
+        assert!(polynomial.to_text().starts_with("folds-polynomial v1\ndims 2\nvariables m1 v1 p t\nterms 2\n"));
+    }
+}